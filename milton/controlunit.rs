@@ -9,6 +9,7 @@ struct InstructionDecoderResult {
     latch_stack: bool,
     write: bool,
     extend_arg: bool,
+    addr_operand: bool,
     latch_ip: bool,
     if_zero: bool,
     jmp: bool,
@@ -63,6 +64,10 @@ impl InstructionDecoder {
                 }
                 _ => (),
             }
+        } else if instr == 0x08 && res.addr_mode == [true, true] {
+            // jump acc: to the absolute address in the accumulator
+            res.alu_op = AluOperation::Right;
+            res.abs_jump = true;
         } else if (0x08..0x0A).contains(&instr) {
             res.jmp = true;
             if instr == 0x09 {
@@ -88,7 +93,13 @@ impl InstructionDecoder {
                 res.write = true;
             } else if self.steps == 1 {
                 self.steps -= 1;
-                res.addr_mode = [false, true];
+                if res.addr_mode == [true, false] {
+                    // call ~ n: the address is read from the stack, n is
+                    // relative to sp before the return address was pushed
+                    res.arg = res.arg.wrapping_add(4);
+                } else {
+                    res.addr_mode = [false, true];
+                }
                 res.latch_ip = true;
                 res.abs_jump = true;
             }
@@ -146,6 +157,11 @@ impl InstructionDecoder {
             }
         } else if instr == 0x11 {
             res.halt = true;
+        } else if instr == 0x12 {
+            res.addr_mode = [true, false];
+            res.addr_operand = true;
+            res.alu_op = AluOperation::Right;
+            res.latch_acc = true;
         } else {
             panic!("Unexpected instruction");
         }
@@ -162,6 +178,8 @@ pub struct ControlUnit {
     instruction_decoder: InstructionDecoder,
     pub instructions_count: usize,
     pub ticks_count: usize,
+    /// Argument of the `halt` the machine stopped at, or acc for `halt acc`.
+    pub status: u16,
 }
 
 impl ControlUnit {
@@ -174,6 +192,7 @@ impl ControlUnit {
             instruction_decoder: InstructionDecoder { steps: 0 },
             instructions_count: 0,
             ticks_count: 0,
+            status: 0,
         }
     }
 
@@ -188,6 +207,10 @@ impl ControlUnit {
             self.instructions_count += 1;
         }
         if res.halt {
+            self.status = match res.addr_mode {
+                [true, true] => self.datapath.acc as u16,
+                _ => res.arg,
+            };
             return true;
         }
 
@@ -199,6 +222,7 @@ impl ControlUnit {
             latch_acc: res.latch_acc,
             latch_stack: res.latch_stack,
             extend_arg: res.extend_arg,
+            addr_operand: res.addr_operand,
             io: res.io,
         });
 
@@ -233,8 +257,7 @@ mod test {
     use super::ControlUnit;
 
     fn conf() -> ControlUnit {
-        let cu = ControlUnit::new([0; 65536], [0; 65536]);
-        cu
+        ControlUnit::new([0; 65536], [0; 65536])
     }
 
     #[test]
@@ -259,8 +282,8 @@ mod test {
         v += 5;
         v += 0x00038276u32;
         v += v;
-        v &= 0x0F0F as u32;
-        v |= 0xA0A0 as u32;
+        v &= 0x0F0F_u32;
+        v |= 0xA0A0_u32;
         v -= 0x00000276u32;
         v *= 3;
         v /= 2;
@@ -302,6 +325,20 @@ mod test {
         assert_eq!(cu.datapath.acc, 9);
     }
 
+    #[test]
+    fn jump_acc() {
+        let mut cu = conf();
+        cu.datapath.mem[16] = 20;
+        cu.mem[0] = 0x0D800004; // load 4
+        cu.mem[1] = 0x0380000C; // add 12
+        cu.mem[2] = 0x0FC00000; // ldrel acc
+        cu.mem[3] = 0x08C00000; // jump acc
+        cu.mem[20] = 0x03800001; // add 1
+        cu.mem[21] = 0x11000000; // halt
+        while !cu.tick() {}
+        assert_eq!(cu.datapath.acc, 21);
+    }
+
     #[test]
     fn call_ret() {
         let mut cu = conf();
@@ -326,6 +363,37 @@ mod test {
         assert_eq!(cu.datapath.acc, 18);
     }
 
+    #[test]
+    fn call_indirect() {
+        let mut cu = conf();
+        cu.mem[0] = 0x0C80FFF8; // spadd -8
+        cu.mem[1] = 0x03800020; // add 32
+        cu.mem[2] = 0x0E400004; // save ~ 4
+        cu.mem[3] = 0x0D800005; // load 5
+        cu.mem[4] = 0x0E400000; // save ~ 0
+        cu.mem[5] = 0x0A400004; // call ~ 4
+        cu.mem[6] = 0x11000000; // halt
+
+        cu.mem[32] = 0x0D400004; // load ~ 4
+        cu.mem[33] = 0x03400008; // add ~ 8
+        cu.mem[34] = 0x0B000000; // ret
+
+        while !cu.tick() {}
+        assert_eq!(cu.datapath.acc, 37);
+        assert_eq!(cu.datapath.sp, u16::MAX - 12);
+    }
+
+    #[test]
+    fn ldsp() {
+        let mut cu = conf();
+        cu.mem[0] = 0x0C80FFF0; // spadd -16
+        cu.mem[1] = 0x12000008; // ldsp 8
+        cu.mem[2] = 0x11000000; // halt
+        while !cu.tick() {}
+        assert_eq!(cu.datapath.acc, (u16::MAX - 12) as u32);
+        assert_eq!(cu.datapath.sp, u16::MAX - 20);
+    }
+
     #[test]
     fn rel() {
         let mut cu = conf();
@@ -353,9 +421,9 @@ mod test {
     #[test]
     fn in_out() {
         let mut cu = conf();
-        cu.datapath.input.append(&mut VecDeque::from_iter(
-            "Hello".as_bytes().into_iter().map(|v| *v),
-        ));
+        cu.datapath
+            .input
+            .append(&mut VecDeque::from_iter("Hello".as_bytes().iter().copied()));
         cu.mem[0] = 0x0D000000; // load # 0
         cu.mem[1] = 0x0E000004; // save # 4
         cu.mem[2] = 0x0D000000; // load # 0
@@ -370,4 +438,21 @@ mod test {
         while !cu.tick() {}
         assert_eq!(std::str::from_utf8(&cu.datapath.output).unwrap(), "Hello");
     }
+
+    #[test]
+    fn halt_status() {
+        let mut cu = conf();
+        cu.mem[0] = 0x08000002; // jump 2
+        cu.mem[1] = 0x11000000; // halt
+        cu.mem[2] = 0x11000007; // halt 7
+        while !cu.tick() {}
+        assert_eq!(cu.ip, 2);
+        assert_eq!(cu.status, 7);
+
+        let mut cu = conf();
+        cu.mem[0] = 0x0D800003; // load 3
+        cu.mem[1] = 0x11C00000; // halt acc
+        while !cu.tick() {}
+        assert_eq!(cu.status, 3);
+    }
 }
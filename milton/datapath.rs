@@ -38,6 +38,8 @@ pub struct DataPathSignals {
     pub latch_acc: bool,
     pub latch_stack: bool,
     pub extend_arg: bool,
+    /// Pass the data address itself to the ALU instead of the value at it.
+    pub addr_operand: bool,
     pub io: bool,
 }
 
@@ -85,7 +87,9 @@ impl DataPath {
             arg_selected
         };
 
-        let operand = if signals.addr_mode[1] {
+        let operand = if signals.addr_operand {
+            data_addr as u32
+        } else if signals.addr_mode[1] {
             mux_arg_acc
         } else {
             data_read
@@ -1,4 +1,4 @@
-use std::{collections::VecDeque, env, error::Error, fs::File, io::Read};
+use std::{collections::VecDeque, env, error::Error, fs::File, io::Read, process};
 
 use controlunit::ControlUnit;
 
@@ -60,6 +60,10 @@ fn main() -> Result<(), Box<dyn Error>> {
     );
 
     println!("{}", std::str::from_utf8(&cu.datapath.output)?);
+    if cu.status != 0 {
+        eprintln!("Halted with status {}", cu.status);
+        process::exit(cu.status.into());
+    }
 
     Ok(())
 }
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+
+use crate::{
+    error::{CompileError, Diagnostics, Location},
+    util::{
+        declared::{Declared, Rest},
+        expression::{Datum, Expression},
+        types::Type,
+    },
+};
+
+/// Infers the types of expressions and reports the values passed or assigned
+/// where a type they can't have is expected. Anything the checker can't infer
+/// is `any` and fits everywhere, so unannotated code is mostly left alone.
+struct Checker<'a> {
+    declared: &'a Declared,
+    /// Variables in scope, innermost last.
+    vars: Vec<(String, Type)>,
+    globals: HashMap<String, Type>,
+    /// Inferred result types of the functions checked so far. A function
+    /// called before its definition or from its own body returns `any`.
+    results: HashMap<String, Type>,
+    /// Types of the values returned with `return` from the enclosing
+    /// functions, innermost last.
+    returns: Vec<Option<Type>>,
+    diagnostics: Diagnostics,
+}
+
+impl<'a> Checker<'a> {
+    fn var(&self, name: &str) -> Type {
+        match self.vars.iter().rev().find(|(v, _)| v == name) {
+            Some((_, ty)) => *ty,
+            None => self.globals.get(name).copied().unwrap_or(Type::Any),
+        }
+    }
+
+    fn expect(
+        &mut self,
+        expected: Type,
+        found: Type,
+        target: impl FnOnce() -> String,
+        at: &Location,
+    ) {
+        if !expected.accepts(found) {
+            self.diagnostics.report(CompileError::TypeMismatch {
+                target: target(),
+                expected,
+                found,
+                at: Some(at.clone()),
+            });
+        }
+    }
+
+    fn function(&mut self, arguments: &[(String, Type)], expr: &Expression) -> Type {
+        let depth = self.vars.len();
+        self.vars.extend_from_slice(arguments);
+        self.returns.push(None);
+        let ty = self.expr(expr);
+        self.vars.truncate(depth);
+
+        match self.returns.pop().flatten() {
+            Some(returned) => ty.join(returned),
+            None => ty,
+        }
+    }
+
+    /// `+` and `-` also move pointers: `(+ p n)`, `(+ n p)` and `(- p n)` are
+    /// of the type of `p`, and `(- p q)` is an int.
+    fn pointer_arithmetic(&mut self, name: &str, a: Type, b: Type, at: &Location) -> Type {
+        match (name, a.is_pointer(), b.is_pointer()) {
+            ("-", true, true) => Type::Int,
+            ("-", true, false) if b == Type::Any => Type::Any,
+            (_, true, false) => a,
+            ("+", false, true) => b,
+            _ => {
+                self.expect(Type::Int, a, || format!("argument 1 of '{}'", name), at);
+                self.expect(Type::Int, b, || format!("argument 2 of '{}'", name), at);
+                match a.is_number() && b.is_number() {
+                    true => Type::Int,
+                    false => Type::Any,
+                }
+            }
+        }
+    }
+
+    /// Checks the types of the arguments of a call and returns its result.
+    fn call(&mut self, name: &str, found: Vec<Type>, at: &Location) -> Type {
+        let Some(signature) = self.declared.fns.get(name) else {
+            return Type::Any;
+        };
+        if let ("+" | "-", [a, b]) = (name, found.as_slice()) {
+            return self.pointer_arithmetic(name, *a, *b, at);
+        }
+
+        for (idx, (expected, found)) in signature.types.iter().zip(&found).enumerate() {
+            let target = || format!("argument {} of '{}'", idx + 1, name);
+            self.expect(*expected, *found, target, at);
+        }
+
+        match self.results.get(name) {
+            Some(result) => *result,
+            None => signature.result,
+        }
+    }
+
+    /// Checks the extra arguments of a call, passed to `&rest` after
+    /// `params` others. Together they are a `ptr`.
+    fn rest(&mut self, name: &str, params: usize, extra: &[Expression], at: &Location) -> Type {
+        let expected = self
+            .declared
+            .fns
+            .get(name)
+            .map_or(Type::Any, |v| v.rest_type);
+        for (idx, arg) in extra.iter().enumerate() {
+            let found = self.expr(arg);
+            let target = || format!("argument {} of '{}'", params + idx + 1, name);
+            self.expect(expected, found, target, at);
+        }
+
+        Type::Ptr
+    }
+
+    fn expr(&mut self, expression: &Expression) -> Type {
+        match expression {
+            Expression::FnDef {
+                name,
+                arguments,
+                expr,
+                ..
+            } => {
+                let arguments: Vec<_> = match self.declared.fns.get(name) {
+                    Some(signature) => arguments
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, v)| match signature.types.get(idx) {
+                            Some(ty) => (v.clone(), *ty),
+                            None if signature.rest == Rest::Array => (v.clone(), Type::Ptr),
+                            None => (v.clone(), Type::Any),
+                        })
+                        .collect(),
+                    None => arguments.iter().map(|v| (v.clone(), Type::Any)).collect(),
+                };
+                // a named function does not see the variables around it
+                let vars = std::mem::take(&mut self.vars);
+                let result = self.function(&arguments, expr);
+                self.vars = vars;
+                self.results.insert(name.clone(), result);
+
+                Type::Any
+            }
+            Expression::Lambda {
+                arguments, expr, ..
+            } => {
+                let arguments: Vec<_> = arguments.iter().map(|v| (v.clone(), Type::Any)).collect();
+                self.function(&arguments, expr);
+
+                Type::Any
+            }
+            Expression::Closure { .. } => Type::Any,
+            Expression::Apply { f, args } => {
+                self.expr(f);
+                for arg in args {
+                    self.expr(arg);
+                }
+
+                Type::Any
+            }
+            Expression::Case { condition, t, f } => {
+                self.expr(condition);
+                let t = self.expr(t);
+
+                t.join(self.expr(f))
+            }
+            Expression::For {
+                var,
+                next_val,
+                while_expr,
+                expr,
+            } => {
+                self.vars.push((var.clone(), Type::Int));
+                self.expr(next_val);
+                self.expr(while_expr);
+                self.expr(expr);
+                self.vars.pop();
+
+                Type::Any
+            }
+            Expression::While { condition, expr } => {
+                self.expr(condition);
+                self.expr(expr);
+
+                Type::Any
+            }
+            Expression::Fn { name, args, at } => {
+                let found = args
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, v)| match v {
+                        Expression::Rest(extra) => self.rest(name, idx, extra, at),
+                        v => self.expr(v),
+                    })
+                    .collect();
+                self.call(name, found, at)
+            }
+            Expression::Field {
+                name,
+                object,
+                value,
+                at,
+                ..
+            } => {
+                let mut found = vec![self.expr(object)];
+                found.extend(value.iter().map(|v| self.expr(v)));
+                self.call(name, found, at)
+            }
+            Expression::Rest(args) => {
+                for arg in args {
+                    self.expr(arg);
+                }
+
+                Type::Ptr
+            }
+            Expression::VarDef {
+                name,
+                ty,
+                init,
+                expr,
+                at,
+            } => {
+                let found = self.expr(init);
+                let ty = match ty {
+                    Some(ty) => {
+                        self.expect(*ty, found, || format!("variable '{}'", name), at);
+                        *ty
+                    }
+                    None => found,
+                };
+                self.vars.push((name.clone(), ty));
+                let ty = self.expr(expr);
+                self.vars.pop();
+
+                ty
+            }
+            Expression::Set { name, expr, at } => {
+                let found = self.expr(expr);
+                let expected = self.var(name);
+                self.expect(expected, found, || format!("variable '{}'", name), at);
+
+                found
+            }
+            Expression::Def { name, init } => {
+                let ty = self.expr(init);
+                self.globals.insert(name.clone(), ty);
+
+                Type::Any
+            }
+            Expression::Break | Expression::Continue => Type::Any,
+            Expression::Return(expr) => {
+                let ty = self.expr(expr);
+                if let Some(returned) = self.returns.last_mut() {
+                    *returned = Some(returned.map_or(ty, |v| v.join(ty)));
+                }
+
+                Type::Any
+            }
+            Expression::Do(exprs) => exprs
+                .iter()
+                .map(|v| self.expr(v))
+                .last()
+                .unwrap_or(Type::Any),
+            Expression::Switch {
+                expr,
+                clauses,
+                default,
+            } => {
+                self.expr(expr);
+                let ty = clauses.iter().map(|(_, v)| self.expr(v)).reduce(Type::join);
+                let default = self.expr(default);
+
+                ty.map_or(default, |v| v.join(default))
+            }
+            Expression::Try { body, var, handler } => {
+                let body = self.expr(body);
+                self.vars.push((var.clone(), Type::Int));
+                let handler = self.expr(handler);
+                self.vars.pop();
+
+                body.join(handler)
+            }
+            Expression::Throw { code, at } => {
+                let found = self.expr(code);
+                self.expect(Type::Int, found, || "argument 1 of 'throw'".to_string(), at);
+
+                Type::Any
+            }
+            // the value is 0 or the last one, and 0 fits everywhere
+            Expression::And(exprs) => exprs
+                .iter()
+                .map(|v| self.expr(v))
+                .last()
+                .unwrap_or(Type::Any),
+            Expression::Or(exprs) => exprs
+                .iter()
+                .map(|v| self.expr(v))
+                .reduce(Type::join)
+                .unwrap_or(Type::Any),
+            Expression::Var(name) => self.var(name),
+            Expression::Str(_) => Type::Str,
+            Expression::Array(_) => Type::Ptr,
+            Expression::Quote(datum) => match datum {
+                Datum::Int(0) => Type::Any,
+                Datum::Int(_) => Type::Int,
+                Datum::List(items) if items.is_empty() => Type::Any,
+                Datum::Symbol(_) | Datum::List(_) => Type::Ptr,
+            },
+            Expression::Value(0) => Type::Any,
+            Expression::Value(_) => Type::Int,
+        }
+    }
+}
+
+/// Checks the top-level expressions in order, so that the result types of
+/// functions are known at the calls after their definitions.
+pub fn check<'a>(
+    expressions: impl IntoIterator<Item = &'a Expression>,
+    declared: &Declared,
+    diagnostics: Diagnostics,
+) -> Result<(), Diagnostics> {
+    let mut checker = Checker {
+        declared,
+        vars: Vec::new(),
+        globals: HashMap::new(),
+        results: HashMap::new(),
+        returns: Vec::new(),
+        diagnostics,
+    };
+    for expression in expressions {
+        checker.expr(expression);
+    }
+
+    match checker.diagnostics.is_empty() {
+        true => Ok(()),
+        false => Err(checker.diagnostics),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::check;
+    use crate::{
+        error::{CompileError, Diagnostics, MAX_ERRORS},
+        parser::parse,
+        util::{source::Source, std_expr::parse_std, types::Type},
+    };
+
+    fn check_text(text: &str) -> Vec<CompileError> {
+        let (std_expressions, mut declared) = parse_std().unwrap();
+        let source = Source::new("test.nl", text);
+        let (expressions, _) = parse(&source, &mut declared, Diagnostics::new(MAX_ERRORS)).unwrap();
+        match check(
+            std_expressions.iter().chain(&expressions),
+            &declared,
+            Diagnostics::new(MAX_ERRORS),
+        ) {
+            Ok(()) => Vec::new(),
+            Err(diagnostics) => diagnostics.errors,
+        }
+    }
+
+    fn mismatch(error: &CompileError) -> (&str, Type, Type) {
+        match error {
+            CompileError::TypeMismatch {
+                target,
+                expected,
+                found,
+                ..
+            } => (target, *expected, *found),
+            _ => panic!("not a type mismatch: {}", error),
+        }
+    }
+
+    #[test]
+    fn well_typed() {
+        let text = "(fn greet (name:str (times:int 1)) (for i (+ i 1) (<= i times) (print name)))
+            (greet \"hi\" 2)
+            (print 0 '(a b) [1 2] (read) (cons 1 0))
+            (let s (+ \"abc\" 1) (print_str s))
+            (let c:char (in) (print_int (- c '0')))
+            (let n 0 (set n \"any\"))
+            (print_int (- \"abc\" \"a\"))
+            (fn pick (x) (case x \"a\" x))
+            (print_int (pick 1))";
+        assert!(check_text(text).is_empty());
+    }
+
+    #[test]
+    fn mismatches() {
+        let errors = check_text(
+            "(print 42)
+            (print_int \"x\")
+            (let s:str 5 (set s [1]))
+            (let n 5 (set n \"x\"))
+            (fn name (x) (switch x (1 \"one\") (else \"many\")))
+            (out (name 1))
+            (fn twice (x:int) (+ x x))
+            (twice (twice \"x\"))
+            (print (twice 2))
+            (- 1 \"a\")
+            (struct point x y)
+            (set_point_y 5 (point_x (point 1 2)))
+            (try (throw \"x\") (catch e (print e)))
+            (print \"a\" \"b\" 42)",
+        );
+        let errors: Vec<_> = errors.iter().map(mismatch).collect();
+        assert_eq!(
+            errors,
+            [
+                ("argument 1 of 'print'", Type::Ptr, Type::Int),
+                ("argument 1 of 'print_int'", Type::Int, Type::Str),
+                ("variable 's'", Type::Str, Type::Int),
+                ("variable 'n'", Type::Int, Type::Str),
+                ("argument 1 of 'out'", Type::Char, Type::Str),
+                ("argument 1 of 'twice'", Type::Int, Type::Str),
+                ("argument 1 of 'print'", Type::Ptr, Type::Int),
+                ("argument 2 of '-'", Type::Int, Type::Str),
+                ("argument 1 of 'set_point_y'", Type::Ptr, Type::Int),
+                ("argument 1 of 'throw'", Type::Int, Type::Str),
+                ("argument 1 of 'print'", Type::Ptr, Type::Int),
+                ("argument 3 of 'print'", Type::Ptr, Type::Int),
+            ]
+        );
+    }
+}
@@ -1,31 +1,99 @@
 use std::collections::HashMap;
 
-use crate::{preprocess::Preprocessed, util::expression::Expression};
+use crate::{
+    error::{CompileError, Limit, LiteralKind},
+    preprocess::Preprocessed,
+    util::{
+        expression::{Datum, Expression},
+        source::{Source, Span},
+    },
+};
 
-fn built_in() -> (Vec<u32>, HashMap<String, u16>) {
-    let mut lines = include_str!("../resources/built-in-asm").lines();
+const MAX_JUMP: usize = i16::MAX as usize;
+const MAX_ADDRESS: usize = u16::MAX as usize + 1;
+/// Start of the static data: it follows the input and output ports, the bump
+/// pointer and the head of the free list.
+const STATIC_DATA: usize = 16;
+/// First words of cons cells and symbols, telling them apart from numbers and
+/// strings at runtime. Must match the ones in `std.nl`.
+const CONS_TAG: u32 = 0x434F4E01;
+const SYMBOL_TAG: u32 = 0x53594D01;
+
+fn built_in() -> Result<(Vec<u32>, HashMap<String, u16>), CompileError> {
+    let built_in_asm = Source::new("built-in-asm", include_str!("../resources/built-in-asm"));
+    let mut start = 0;
+    let mut lines = built_in_asm
+        .text
+        .split('\n')
+        .enumerate()
+        .map(|(idx, line)| {
+            let span = Span {
+                start,
+                end: start + line.trim_end().len(),
+                line: idx + 1,
+                col: 1,
+            };
+            start += line.len() + 1;
+            (line, span)
+        });
+    let malformed = |span| CompileError::MalformedLiteral {
+        kind: LiteralKind::Instruction,
+        at: Some(built_in_asm.locate(span)),
+    };
     let mut instructions: Vec<u32> = vec![0];
     let mut fn_addresses: HashMap<String, u16> = HashMap::new();
 
-    while let Some(line) = lines.next() {
+    while let Some((line, span)) = lines.next() {
         let mut words = line.split_ascii_whitespace();
-        let fn_name = words.next().unwrap().to_string();
-        fn_addresses.insert(fn_name, instructions.len() as u16);
-        for _ in 0..words.next().unwrap().parse::<usize>().unwrap() {
-            instructions.push(
-                u32::from_str_radix(lines.next().unwrap().split_whitespace().next().unwrap(), 16)
-                    .unwrap(),
-            );
+        let Some(fn_name) = words.next() else {
+            continue;
+        };
+        fn_addresses.insert(fn_name.to_string(), instructions.len() as u16);
+        let count = words
+            .next()
+            .and_then(|v| v.parse::<usize>().ok())
+            .ok_or_else(|| malformed(span))?;
+        for _ in 0..count {
+            let (line, span) = lines.next().ok_or_else(|| malformed(span))?;
+            let instr = line
+                .split_whitespace()
+                .next()
+                .and_then(|v| u32::from_str_radix(v, 16).ok())
+                .ok_or_else(|| malformed(span))?;
+            instructions.push(instr);
         }
     }
 
-    (instructions, fn_addresses)
+    Ok((instructions, fn_addresses))
+}
+
+fn jump(opcode: u32, offset: i32) -> Result<u32, CompileError> {
+    if offset.unsigned_abs() as usize > MAX_JUMP {
+        return Err(CompileError::BackendLimit {
+            limit: Limit::JumpDistance,
+            size: offset.unsigned_abs() as usize,
+            max: MAX_JUMP,
+            at: None,
+        });
+    }
+
+    Ok(opcode | offset as i16 as u16 as u32)
+}
+
+fn lookup<'a, T>(map: &'a HashMap<String, T>, name: &str) -> Result<&'a T, CompileError> {
+    map.get(name).ok_or_else(|| CompileError::UnknownName {
+        name: name.to_string(),
+        at: None,
+    })
 }
 
+#[derive(Clone)]
 enum Var {
     Memory(u16),
     Stack(u16),
     InWord(u16),
+    /// Index of a captured variable in the closure environment.
+    Env(u16),
 }
 
 impl Var {
@@ -34,52 +102,571 @@ impl Var {
             Var::Memory(arg) => *arg as u32,
             Var::Stack(arg) => *arg as u32 | 0x00400000u32,
             Var::InWord(arg) => *arg as u32 | 0x00800000u32,
+            Var::Env(_) => unreachable!("captured variables are accessed through the environment"),
+        }
+    }
+}
+
+/// Hidden argument holding the closure pointer. It is passed before all other
+/// arguments by `apply`, so functions that don't use it simply ignore it.
+const ENV: &str = " env";
+
+/// Placeholders for jumps out of a loop, patched once the loop is laid out.
+const BREAK: u32 = 0xF0000000;
+const CONTINUE: u32 = 0xF1000000;
+/// Placeholders for a call of a function and for loading its address. They
+/// hold an index into `Scope::fn_refs` and are patched once all functions are
+/// laid out, so a function may be used before its definition.
+const CALL: u32 = 0xF2000000;
+const FN_ADDRESS: u32 = 0xF3000000;
+/// Placeholder for `jump acc` through a jump table, holding an index into
+/// `Scope::jump_tables`. The table is filled once the jump has its address.
+const JUMP_TABLE: u32 = 0xF4000000;
+/// Placeholder for loading the address of the instruction the given number of
+/// instructions ahead, patched once the code has its final position.
+const HANDLER_ADDRESS: u32 = 0xF5000000;
+/// Status of the machine halted by a `throw` outside of any `try`.
+const UNCAUGHT: u32 = 4;
+/// A `switch` uses a jump table if it has at least that many keys and they
+/// take at least half of the table.
+const MIN_TABLE_KEYS: usize = 3;
+
+/// Variables visible to the translated expression and the shape of the stack
+/// around it.
+struct Scope {
+    /// Bindings of each name, innermost last.
+    vars: HashMap<String, Vec<Var>>,
+    /// Cells of `def` variables, used when there is no local binding.
+    globals: HashMap<String, Var>,
+    in_fn: bool,
+    bounds_check: bool,
+    /// Words pushed on the stack since the start of the function or program.
+    depth: i16,
+    /// Stack depth right after the frame of each enclosing loop, innermost last.
+    loops: Vec<i16>,
+    /// Stack depth right after the handler record of each enclosing `try`,
+    /// innermost last. A record holds the address of the record of the
+    /// enclosing `try` and the address of the handler; the innermost one is
+    /// pointed to by a static cell, so `throw` finds it from any function.
+    tries: Vec<i16>,
+    /// Static cells holding the address of the innermost handler record, 0
+    /// outside of any `try`, and the code being thrown. Allocated by the
+    /// first `try` or `throw`.
+    exception_cells: Option<(u16, u16)>,
+    /// Addresses of interned symbols in the static memory.
+    symbols: HashMap<String, u32>,
+    /// Functions referenced by `CALL` and `FN_ADDRESS` placeholders.
+    fn_refs: Vec<String>,
+    /// Static data words holding addresses of functions, by their offset.
+    fn_cells: Vec<(usize, String)>,
+    /// Offsets of jump tables in the static data and their targets relative
+    /// to the `JUMP_TABLE` placeholder.
+    jump_tables: Vec<(usize, Vec<i32>)>,
+}
+
+impl Scope {
+    fn new(in_fn: bool) -> Self {
+        Scope {
+            vars: HashMap::new(),
+            globals: HashMap::new(),
+            in_fn,
+            bounds_check: false,
+            depth: 0,
+            loops: Vec::new(),
+            tries: Vec::new(),
+            exception_cells: None,
+            symbols: HashMap::new(),
+            fn_refs: Vec::new(),
+            fn_cells: Vec::new(),
+            jump_tables: Vec::new(),
+        }
+    }
+
+    fn spadd(&mut self, offset: i16) {
+        self.vars.values_mut().flatten().for_each(|v| {
+            if let Var::Stack(n) = v {
+                *n = (*n as i16 + -4 * offset) as u16;
+            }
+        });
+        self.depth -= offset;
+    }
+
+    /// Binds the name, shadowing its previous binding until `unbind`.
+    fn bind(&mut self, name: &str, var: Var) {
+        self.vars.entry(name.to_string()).or_default().push(var);
+    }
+
+    fn unbind(&mut self, name: &str) {
+        if let Some(bindings) = self.vars.get_mut(name) {
+            bindings.pop();
+            if bindings.is_empty() {
+                self.vars.remove(name);
+            }
+        }
+    }
+
+    /// Scope of a function body, sharing the globals and the exception cells.
+    /// It borrows the symbol table, the function references and the jump
+    /// tables, which have to be given back once the body is translated.
+    fn function(&mut self) -> Self {
+        Scope {
+            globals: self.globals.clone(),
+            bounds_check: self.bounds_check,
+            exception_cells: self.exception_cells,
+            symbols: std::mem::take(&mut self.symbols),
+            fn_refs: std::mem::take(&mut self.fn_refs),
+            fn_cells: std::mem::take(&mut self.fn_cells),
+            jump_tables: std::mem::take(&mut self.jump_tables),
+            ..Scope::new(true)
+        }
+    }
+
+    /// Placeholder referring to the function, see `CALL`.
+    fn fn_ref(&mut self, placeholder: u32, name: &str) -> u32 {
+        let name = self.callee(name).to_string();
+        let idx = match self.fn_refs.iter().position(|v| *v == name) {
+            Some(idx) => idx,
+            None => {
+                self.fn_refs.push(name);
+                self.fn_refs.len() - 1
+            }
+        };
+
+        placeholder | idx as u32
+    }
+
+    /// Name of the function actually called: in checked mode array accesses
+    /// go through the versions that trap on out-of-range indexes.
+    fn callee<'a>(&self, name: &'a str) -> &'a str {
+        match name {
+            "get" if self.bounds_check => "get_checked",
+            "put" if self.bounds_check => "put_checked",
+            _ => name,
+        }
+    }
+
+    /// The innermost binding of the name.
+    fn var(&self, name: &str) -> Result<&Var, CompileError> {
+        match self.vars.get(name) {
+            Some(bindings) => Ok(bindings.last().unwrap()),
+            None => lookup(&self.globals, name),
+        }
+    }
+
+    /// Instruction dropping everything pushed since the stack was `depth` words
+    /// deep.
+    fn unwind_to(&self, depth: i16) -> Option<u32> {
+        let words = self.depth - depth;
+        (words != 0).then(|| 0x0C800000 | (words * 4) as u16 as u32) // spadd x
+    }
+
+    /// Addresses of the handler and thrown code cells, see `exception_cells`.
+    fn exception_cells(&mut self, data: &mut Vec<u8>) -> (u32, u32) {
+        let (handler, thrown) = *self.exception_cells.get_or_insert_with(|| {
+            let handler = (data.len() + STATIC_DATA) as u16;
+            data.extend_from_slice(&[0; 8]);
+            (handler, handler + 4)
+        });
+
+        (handler as u32, thrown as u32)
+    }
+
+    /// Instructions leaving the `try` bodies entered since the stack was
+    /// `depth` words deep: the handler of the outermost one becomes current
+    /// again. acc is kept.
+    fn leave_tries(&self, depth: i16) -> Vec<u32> {
+        let Some(record) = self.tries.iter().find(|v| **v > depth) else {
+            return Vec::new();
+        };
+        let handler = self.exception_cells.expect("allocated by try").0 as u32;
+        let offset = (self.depth - record) as u32 * 4;
+
+        vec![
+            0x0E400004 + offset,  // save ~ x+4, where x is offset of the record
+            0x0D400000 + offset,  // load ~ x - enclosing record
+            0x0E000000 | handler, // save # handler
+            0x0D400004 + offset,  // load ~ x+4
+        ]
+    }
+
+    fn loop_depth(&self, form: &str) -> Result<i16, CompileError> {
+        self.loops
+            .last()
+            .copied()
+            .ok_or_else(|| CompileError::Misplaced {
+                form: form.to_string(),
+                at: None,
+            })
+    }
+}
+
+/// Loads the variable into acc.
+fn load_var(scope: &Scope, name: &str) -> Result<Vec<u32>, CompileError> {
+    match scope.var(name)? {
+        Var::Env(idx) => Ok(vec![
+            0x0D000000 | scope.var(ENV)?.to_arg(), // load env
+            0x03800000 + (*idx as u32 + 1) * 4,    // add x, where x is offset in env
+            0x0C80FFFC,                            // spadd -4
+            0x0E400000,                            // save ~ 0
+            0x0F400000,                            // ldrel ~ 0
+            0x0C800004,                            // spadd 4
+        ]),
+        var => Ok(vec![0x0D000000 | var.to_arg()]), // load
+    }
+}
+
+/// Saves acc into the variable, acc is kept.
+fn save_var(scope: &mut Scope, name: &str) -> Result<Vec<u32>, CompileError> {
+    match scope.var(name)? {
+        Var::Env(idx) => {
+            let idx = *idx;
+            scope.spadd(-2);
+            let env = scope.var(ENV).map(Var::to_arg);
+            scope.spadd(2);
+
+            Ok(vec![
+                0x0C80FFF8,                        // spadd -8
+                0x0E400004,                        // save ~ 4
+                0x0D000000 | env?,                 // load env
+                0x03800000 + (idx as u32 + 1) * 4, // add x, where x is offset in env
+                0x0E400000,                        // save ~ 0
+                0x0D400004,                        // load ~ 4
+                0x10400000,                        // svrel ~ 0
+                0x0C800008,                        // spadd 8
+            ])
         }
+        var => Ok(vec![0x0E000000 | var.to_arg()]), // save
     }
 }
 
-fn spadd(vars: &mut HashMap<String, Var>, offset: i16) {
-    vars.iter_mut().for_each(|v| {
-        if let Var::Stack(n) = v.1 {
-            *n = (*n as i16 + -4 * offset) as u16;
+/// Lays out the datum in the static memory and returns its value.
+fn quoted(datum: &Datum, scope: &mut Scope, data: &mut Vec<u8>) -> u32 {
+    match datum {
+        Datum::Int(num) => *num as u32,
+        Datum::Symbol(name) => {
+            if let Some(pointer) = scope.symbols.get(name) {
+                return *pointer;
+            }
+            let pointer = (data.len() + STATIC_DATA) as u32;
+            data.extend_from_slice(&SYMBOL_TAG.to_le_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+            scope.symbols.insert(name.clone(), pointer);
+
+            pointer
+        }
+        Datum::List(items) => {
+            let mut tail = 0;
+            for item in items.iter().rev() {
+                let car = quoted(item, scope, data);
+                let pointer = (data.len() + STATIC_DATA) as u32;
+                for word in [CONS_TAG, car, tail] {
+                    data.extend_from_slice(&word.to_le_bytes());
+                }
+                tail = pointer;
+            }
+
+            tail
         }
-    });
+    }
+}
+
+/// Replaces `break` and `continue` placeholders of the loop with jumps. `break`
+/// jumps right past the instructions laid out so far.
+fn patch_loop(instructions: &mut [u32], continue_addr: usize) -> Result<(), CompileError> {
+    let break_addr = instructions.len();
+    for (idx, instruction) in instructions.iter_mut().enumerate() {
+        let target = match *instruction {
+            BREAK => break_addr,
+            CONTINUE => continue_addr,
+            _ => continue,
+        };
+        *instruction = jump(0x08000000, target as i32 - idx as i32)?;
+    }
+
+    Ok(())
+}
+
+/// Replaces the `CALL`, `FN_ADDRESS`, `JUMP_TABLE` and `HANDLER_ADDRESS`
+/// placeholders and fills the static data words with code addresses, once all
+/// the code is laid out.
+fn patch_addresses(
+    instructions: &mut [u32],
+    data: &mut [u8],
+    scope: &Scope,
+    fn_addresses: &HashMap<String, u16>,
+) -> Result<(), CompileError> {
+    for (address, instruction) in instructions.iter_mut().enumerate() {
+        let idx = (*instruction & 0x00FFFFFF) as usize;
+        let opcode = match *instruction & 0xFF000000 {
+            CALL => 0x0A000000,       // call
+            FN_ADDRESS => 0x0D800000, // load
+            JUMP_TABLE => {
+                let (offset, targets) = &scope.jump_tables[idx];
+                for (slot, target) in targets.iter().enumerate() {
+                    let target = (address as i32 + target) as u32;
+                    data[offset + slot * 4..offset + slot * 4 + 4]
+                        .copy_from_slice(&target.to_le_bytes());
+                }
+                *instruction = 0x08C00000; // jump acc
+                continue;
+            }
+            HANDLER_ADDRESS => {
+                *instruction = 0x0D800000 | (address + idx) as u32; // load
+                continue;
+            }
+            _ => continue,
+        };
+        *instruction = opcode | *lookup(fn_addresses, &scope.fn_refs[idx])? as u32;
+    }
+    for (offset, name) in &scope.fn_cells {
+        let address = *lookup(fn_addresses, name)? as u32;
+        data[*offset..*offset + 4].copy_from_slice(&address.to_le_bytes());
+    }
+
+    Ok(())
+}
+
+/// Argument holding the number: in the instruction word if it fits there,
+/// otherwise in the static memory.
+fn constant(num: i32, data: &mut Vec<u8>) -> Var {
+    match i16::try_from(num) {
+        Ok(num) => Var::InWord(num as u16),
+        Err(_) => {
+            let var = Var::Memory((data.len() + STATIC_DATA) as u16);
+            data.extend_from_slice(&num.to_le_bytes());
+            var
+        }
+    }
+}
+
+/// Jump of a `switch` to one of its branches, patched once the branches are
+/// laid out: position, opcode and the branch, `None` for the default one.
+type Exit = (usize, u32, Option<usize>);
+
+/// Binary search for the key stored at `key` among the sorted `keys`, each
+/// with its branch. The search jumps to the branch of the key if it is found
+/// and to the default branch otherwise.
+fn switch_search(
+    keys: &[(i32, usize)],
+    key: u32,
+    instructions: &mut Vec<u32>,
+    exits: &mut Vec<Exit>,
+    data: &mut Vec<u8>,
+) -> Result<(), CompileError> {
+    instructions.push(0x0D000000 | key); // load # key
+    if let [(value, branch)] = keys {
+        instructions.push(0x04000000 | constant(*value, data).to_arg()); // sub value
+        exits.push((instructions.len(), 0x09000000, Some(*branch)));
+        instructions.push(0); // jifz -> branch
+        exits.push((instructions.len(), 0x08000000, None));
+        instructions.push(0); // jump -> default
+
+        return Ok(());
+    }
+
+    let mid = keys.len() / 2;
+    instructions.push(0x04000000 | constant(keys[mid].0, data).to_arg()); // sub pivot
+    instructions.push(0x00C00000); // sign acc
+    let test = instructions.len();
+    instructions.push(0); // jifz -> upper half
+    switch_search(&keys[..mid], key, instructions, exits, data)?;
+    instructions[test] = jump(0x09000000, (instructions.len() - test) as i32)?;
+    switch_search(&keys[mid..], key, instructions, exits, data)
+}
+
+/// Compiles `switch`. The key is kept in a static memory cell, which is safe
+/// since the dispatch calls nothing. Dense keys are dispatched through a jump
+/// table, sparse ones with a binary search.
+fn translate_switch(
+    expr: &Expression,
+    clauses: &[(Vec<i32>, Expression)],
+    default: &Expression,
+    scope: &mut Scope,
+    data: &mut Vec<u8>,
+) -> Result<Vec<u32>, CompileError> {
+    let mut keys: Vec<(i32, usize)> = clauses
+        .iter()
+        .enumerate()
+        .flat_map(|(branch, (keys, _))| keys.iter().map(move |v| (*v, branch)))
+        .collect();
+    keys.sort();
+    let key = (data.len() + STATIC_DATA) as u32;
+    data.extend_from_slice(&[0; 4]);
+
+    let mut instructions = translate(expr, scope, data)?;
+    let mut exits: Vec<Exit> = Vec::new();
+    let mut table = None;
+    let (min, max) = match (keys.first(), keys.last()) {
+        (Some(min), Some(max)) => (min.0, max.0),
+        _ => (0, -1),
+    };
+    let size = (max as i64 - min as i64 + 1) as usize;
+    if keys.len() >= MIN_TABLE_KEYS && size <= keys.len() * 2 {
+        instructions.push(0x04000000 | constant(min, data).to_arg()); // sub min
+        instructions.push(0x0E000000 | key); // save # key
+        instructions.push(0x00C00000); // sign acc
+        instructions.push(jump(0x09000000, 2)?); // jifz +2
+        exits.push((instructions.len(), 0x08000000, None));
+        instructions.push(0); // jump -> default
+        instructions.push(0x0D000000 | key); // load # key
+        instructions.push(0x04000000 | constant(size as i32, data).to_arg()); // sub size
+        instructions.push(0x00C00000); // sign acc
+        exits.push((instructions.len(), 0x09000000, None));
+        instructions.push(0); // jifz -> default
+        let offset = data.len();
+        data.extend_from_slice(&vec![0; size * 4]);
+        instructions.push(0x0D000000 | key); // load # key
+        instructions.push(0x05800004); // mul 4
+        instructions.push(0x03000000 | constant((offset + STATIC_DATA) as i32, data).to_arg()); // add table
+        instructions.push(0x0FC00000); // ldrel acc
+        table = Some((instructions.len(), scope.jump_tables.len()));
+        instructions.push(JUMP_TABLE | scope.jump_tables.len() as u32); // jump acc
+        scope.jump_tables.push((offset, Vec::new()));
+    } else if !keys.is_empty() {
+        // `sub` in the search overflows between a negative and a non-negative
+        // number, so the sign of the key picks the keys to search first
+        instructions.push(0x0E000000 | key); // save # key
+        let (negative, other) = keys.split_at(keys.partition_point(|v| v.0 < 0));
+        if negative.is_empty() || other.is_empty() {
+            switch_search(&keys, key, &mut instructions, &mut exits, data)?;
+        } else {
+            instructions.push(0x00C00000); // sign acc
+            let test = instructions.len();
+            instructions.push(0); // jifz -> non-negative keys
+            switch_search(negative, key, &mut instructions, &mut exits, data)?;
+            instructions[test] = jump(0x09000000, (instructions.len() - test) as i32)?;
+            switch_search(other, key, &mut instructions, &mut exits, data)?;
+        }
+    }
+
+    let mut ends = Vec::new();
+    let default_start = instructions.len();
+    instructions.append(&mut translate(default, scope, data)?);
+    let mut starts = Vec::new();
+    for (_, expr) in clauses {
+        ends.push(instructions.len());
+        instructions.push(0); // jump -> end
+        starts.push(instructions.len());
+        instructions.append(&mut translate(expr, scope, data)?);
+    }
+    let end = instructions.len();
+    for pos in ends {
+        instructions[pos] = jump(0x08000000, (end - pos) as i32)?; // jump -> end
+    }
+    let start = |branch: Option<usize>| branch.map_or(default_start, |v| starts[v]) as i32;
+    for (pos, opcode, branch) in exits {
+        instructions[pos] = jump(opcode, start(branch) - pos as i32)?;
+    }
+    if let Some((pos, idx)) = table {
+        let mut targets = vec![start(None) - pos as i32; size];
+        for (value, branch) in keys {
+            targets[(value - min) as usize] = start(Some(branch)) - pos as i32;
+        }
+        scope.jump_tables[idx].1 = targets;
+    }
+
+    Ok(instructions)
+}
+
+/// Stores the extra arguments of a variadic call as an array `offset` words
+/// above the stack pointer and loads the pointer to it.
+fn rest_array(
+    extra: &[Expression],
+    offset: usize,
+    scope: &mut Scope,
+    data: &mut Vec<u8>,
+) -> Result<Vec<u32>, CompileError> {
+    let offset = offset as u32 * 4;
+    let mut instructions = vec![
+        0x0D800000 | extra.len() as u32, // load n
+        0x0E400000 + offset,             // save ~ x
+    ];
+    for (idx, arg) in extra.iter().enumerate() {
+        instructions.append(&mut translate(arg, scope, data)?);
+        instructions.push(0x0E400000 + offset + 4 * (idx as u32 + 1)); // save ~ x+i
+    }
+    instructions.push(0x12000000 | offset); // ldsp x
+
+    Ok(instructions)
 }
 
 fn translate(
     expr: &Expression,
-    vars: &mut HashMap<String, Var>,
-    fn_addresses: &HashMap<String, u16>,
+    scope: &mut Scope,
     data: &mut Vec<u8>,
-) -> Vec<u32> {
+) -> Result<Vec<u32>, CompileError> {
     match expr {
         Expression::FnDef {
             name: _,
             arguments,
+            captures,
             expr,
         } => {
-            let mut fn_vars: HashMap<String, Var> = HashMap::from_iter(
-                arguments
-                    .iter()
-                    .rev()
-                    .enumerate()
-                    .map(|(i, v)| (v.clone(), Var::Stack((i + 1) as u16 * 4))),
-            );
-            let mut instructions = translate(expr, &mut fn_vars, fn_addresses, data);
+            let mut fn_scope = scope.function();
+            for (i, capture) in captures.iter().enumerate() {
+                fn_scope.bind(capture, Var::Env(i as u16));
+            }
+            fn_scope.bind(ENV, Var::Stack((arguments.len() + 1) as u16 * 4));
+            for (i, argument) in arguments.iter().rev().enumerate() {
+                fn_scope.bind(argument, Var::Stack((i + 1) as u16 * 4));
+            }
+            let mut instructions = translate(expr, &mut fn_scope, data)?;
             instructions.push(0x0B000000); // ret
+            scope.symbols = fn_scope.symbols;
+            scope.fn_refs = fn_scope.fn_refs;
+            scope.fn_cells = fn_scope.fn_cells;
+            scope.jump_tables = fn_scope.jump_tables;
+            scope.exception_cells = fn_scope.exception_cells;
 
-            instructions
+            Ok(instructions)
         }
         Expression::Case { condition, t, f } => {
-            let mut c_instructions = translate(condition, vars, fn_addresses, data);
-            let mut t_instructions = translate(t, vars, fn_addresses, data);
-            let mut f_instructions = translate(f, vars, fn_addresses, data);
-            t_instructions.push(0x08000000 + f_instructions.len() as u32 + 1); // jump -> end
-            c_instructions.push(0x09000000 + t_instructions.len() as u32 + 1); // jifz -> end
+            let mut c_instructions = translate(condition, scope, data)?;
+            let mut t_instructions = translate(t, scope, data)?;
+            let mut f_instructions = translate(f, scope, data)?;
+            t_instructions.push(jump(0x08000000, f_instructions.len() as i32 + 1)?); // jump -> end
+            c_instructions.push(jump(0x09000000, t_instructions.len() as i32 + 1)?); // jifz -> end
             c_instructions.append(&mut t_instructions);
             c_instructions.append(&mut f_instructions);
 
-            c_instructions
+            Ok(c_instructions)
+        }
+        Expression::Switch {
+            expr,
+            clauses,
+            default,
+        } => translate_switch(expr, clauses, default, scope, data),
+        Expression::And(exprs) => {
+            // a zero skips the rest, staying in the accumulator as the value
+            let mut parts = exprs
+                .iter()
+                .map(|v| translate(v, scope, data))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut instructions = parts.pop().unwrap_or_default();
+            while let Some(mut part) = parts.pop() {
+                part.push(jump(0x09000000, instructions.len() as i32 + 1)?); // jifz -> end
+                part.append(&mut instructions);
+                instructions = part;
+            }
+
+            Ok(instructions)
+        }
+        Expression::Or(exprs) => {
+            // a non-zero value jumps past the rest; `jifz` steps over that jump
+            let mut parts = exprs
+                .iter()
+                .map(|v| translate(v, scope, data))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut instructions = parts.pop().unwrap_or_default();
+            while let Some(mut part) = parts.pop() {
+                part.push(jump(0x09000000, 2)?); // jifz -> next
+                part.push(jump(0x08000000, instructions.len() as i32 + 1)?); // jump -> end
+                part.append(&mut instructions);
+                instructions = part;
+            }
+
+            Ok(instructions)
         }
         Expression::For {
             var,
@@ -89,127 +676,399 @@ fn translate(
         } => {
             let mut instructions = Vec::new();
 
-            spadd(vars, -2);
-            vars.insert(var.clone(), Var::Stack(4)); // variable
+            scope.spadd(-2);
+            scope.bind(var, Var::Stack(4)); // variable
 
             instructions.push(0x0C80FFF8); // spadd -8
             instructions.push(0x0D800000); // load 0
-            instructions.push(0x0E000000 | vars[var].to_arg()); // save # var
+            instructions.push(0x0E000000 | scope.var(var)?.to_arg()); // save # var
             instructions.push(0x0D800000); // load 0
             instructions.push(0x0E400000); // save ~ 0 - init cumulative with 0
 
+            scope.loops.push(scope.depth);
             let next_val_addr = instructions.len();
 
-            let mut next_val_instructions = translate(next_val, vars, fn_addresses, data);
+            let mut next_val_instructions = translate(next_val, scope, data)?;
             instructions.append(&mut next_val_instructions);
-            instructions.push(0x0E000000 | vars[var].to_arg()); // save # var
+            instructions.push(0x0E000000 | scope.var(var)?.to_arg()); // save # var
 
-            let mut while_instructions = translate(while_expr, vars, fn_addresses, data);
+            let mut while_instructions = translate(while_expr, scope, data)?;
             instructions.append(&mut while_instructions);
 
-            let mut expr_instructions = translate(expr, vars, fn_addresses, data);
-            instructions.push(0x09000000 + expr_instructions.len() as u32 + 4); // jifz + -> end
+            let mut expr_instructions = translate(expr, scope, data)?;
+            instructions.push(jump(0x09000000, expr_instructions.len() as i32 + 4)?); // jifz + -> end
             instructions.append(&mut expr_instructions);
             instructions.push(0x03400000); // add ~ 0
             instructions.push(0x0E400000); // save ~ 0
-            instructions.push(
-                0x08000000
-                    | (next_val_addr as i32 - instructions.len() as i32) as i16 as u16 as u32,
-            ); // jump - -> next_val
+            instructions.push(jump(
+                0x08000000,
+                next_val_addr as i32 - instructions.len() as i32,
+            )?); // jump - -> next_val
+            patch_loop(&mut instructions, next_val_addr)?;
+            scope.loops.pop();
             instructions.push(0x0D400000); // load ~ 0 :end
             instructions.push(0x0C800008); // spadd 8
 
-            vars.remove(var);
-            spadd(vars, 2);
+            scope.unbind(var);
+            scope.spadd(2);
+
+            Ok(instructions)
+        }
+        Expression::While { condition, expr } => {
+            let mut instructions = Vec::new();
+
+            scope.spadd(-1);
+            instructions.push(0x0C80FFFC); // spadd -4
+            instructions.push(0x0D800000); // load 0
+            instructions.push(0x0E400000); // save ~ 0 - result is 0 if body never runs
+
+            scope.loops.push(scope.depth);
+            let condition_addr = instructions.len();
+            let mut c_instructions = translate(condition, scope, data)?;
+            let mut expr_instructions = translate(expr, scope, data)?;
+            expr_instructions.push(0x0E400000); // save ~ 0
+            let loop_len = c_instructions.len() + expr_instructions.len() + 1;
+            expr_instructions.push(jump(0x08000000, -(loop_len as i32))?); // jump - -> condition
+            c_instructions.push(jump(0x09000000, expr_instructions.len() as i32 + 1)?); // jifz + -> end
+            instructions.append(&mut c_instructions);
+            instructions.append(&mut expr_instructions);
+            patch_loop(&mut instructions, condition_addr)?;
+            scope.loops.pop();
+            instructions.push(0x0D400000); // load ~ 0 :end
+            instructions.push(0x0C800004); // spadd 4
 
-            instructions
+            scope.spadd(1);
+
+            Ok(instructions)
         }
-        Expression::Fn { name, args } => {
+        Expression::Fn { name, args, .. } => {
+            // extra arguments of a variadic function are stored right above
+            // the others as an array: the count, then the arguments
+            let size = match args.last() {
+                Some(Expression::Rest(extra)) => args.len() + extra.len() + 1,
+                _ => args.len(),
+            };
             let mut instructions = Vec::new();
-            instructions.push(0x0C800000 | (args.len() as i16 * -4) as u16 as u32); // spadd -x, where x = args.len() * 4
+            instructions.push(0x0C800000 | (size as i16 * -4) as u16 as u32); // spadd -x, where x = size * 4
 
-            spadd(vars, -(args.len() as u16 as i16));
+            scope.spadd(-(size as u16 as i16));
 
             for (idx, arg) in args
                 .iter()
                 .enumerate()
                 .map(|(idx, v)| (args.len() - idx - 1, v))
             {
-                let mut arg_instructions = translate(arg, vars, fn_addresses, data);
+                let mut arg_instructions = match arg {
+                    Expression::Rest(extra) => rest_array(extra, args.len(), scope, data)?,
+                    _ => translate(arg, scope, data)?,
+                };
                 instructions.append(&mut arg_instructions);
                 instructions.push(0x0E400000 + 4 * idx as u32); // save ~n
             }
-            instructions.push(0x0A000000 | fn_addresses[name] as u32); // call
-            instructions.push(0x0C800000 | (args.len() * 4) as u32); // spadd x
+            instructions.push(scope.fn_ref(CALL, name)); // call
+            instructions.push(0x0C800000 | (size * 4) as u32); // spadd x
+
+            scope.spadd(size as u16 as i16);
 
-            spadd(vars, args.len() as u16 as i16);
+            Ok(instructions)
+        }
+        Expression::Field {
+            object,
+            offset,
+            value,
+            ..
+        } => {
+            let mut instructions = translate(object, scope, data)?;
+            if *offset != 0 {
+                instructions.push(0x03800000 | *offset as u32); // add x, where x is offset
+            }
+            let Some(value) = value else {
+                instructions.push(0x0FC00000); // ldrel acc
+                return Ok(instructions);
+            };
+
+            instructions.push(0x0C80FFFC); // spadd -4
+            instructions.push(0x0E400000); // save ~ 0 - field address
+            scope.spadd(-1);
+            instructions.append(&mut translate(value, scope, data)?);
+            scope.spadd(1);
+            instructions.push(0x10400000); // svrel ~ 0
+            instructions.push(0x0C800004); // spadd 4
 
-            instructions
+            Ok(instructions)
         }
-        Expression::VarDef { name, init, expr } => {
+        Expression::VarDef {
+            name, init, expr, ..
+        } => {
             let mut instructions = Vec::new();
 
-            spadd(vars, -1);
-            vars.insert(name.clone(), Var::Stack(0)); // variable
+            scope.spadd(-1);
             instructions.push(0x0C80FFFC); // spadd -4
 
-            let mut init_instructions = translate(init, vars, fn_addresses, data);
+            let mut init_instructions = translate(init, scope, data)?;
             instructions.append(&mut init_instructions);
-            instructions.push(0x0E000000 | vars[name].to_arg()); // save # x, where x is var addr
+            scope.bind(name, Var::Stack(0)); // variable, not visible in init
+            instructions.push(0x0E000000 | scope.var(name)?.to_arg()); // save # x, where x is var addr
 
-            let mut expr_instructions = translate(expr, vars, fn_addresses, data);
+            let mut expr_instructions = translate(expr, scope, data)?;
             instructions.append(&mut expr_instructions);
 
             instructions.push(0x0C800004); // spadd 4
-            vars.remove(name);
-            spadd(vars, 1);
+            scope.unbind(name);
+            scope.spadd(1);
+
+            Ok(instructions)
+        }
+        Expression::Set { name, expr, .. } => {
+            let mut instructions = translate(expr, scope, data)?;
+            instructions.append(&mut save_var(scope, name)?);
+
+            Ok(instructions)
+        }
+        Expression::Lambda { .. } | Expression::Def { .. } => {
+            unreachable!("lambdas and globals are hoisted by preprocessing")
+        }
+        Expression::Rest(_) => unreachable!("extra arguments only end a call"),
+        Expression::Closure { name, captures } => {
+            if captures.is_empty() {
+                let pointer = data.len() + STATIC_DATA;
+                scope
+                    .fn_cells
+                    .push((data.len(), scope.callee(name).to_string()));
+                data.extend_from_slice(&[0; 4]);
+
+                return Ok(vec![0x0D800000 | pointer as u32]); // load
+            }
+
+            let alloc = scope.fn_ref(CALL, "alloc");
+            let address = scope.fn_ref(FN_ADDRESS, name);
+            let mut instructions = vec![
+                0x0C80FFFC,                                   // spadd -4
+                0x0D800000 + (captures.len() as u32 + 1) * 4, // load x, where x is closure size
+                0x0E400000,                                   // save ~ 0
+                alloc,                                        // call alloc
+                0x0C800004,                                   // spadd 4
+                0x0C80FFF8,                                   // spadd -8
+                0x0E400004,                                   // save ~ 4 - closure
+                0x0E400000,                                   // save ~ 0 - cursor
+                address,                                      // load x, where x is code address
+                0x10400000,                                   // svrel ~ 0
+            ];
+            scope.spadd(-2);
+            for capture in captures {
+                instructions.push(0x0D400000); // load ~ 0
+                instructions.push(0x03800004); // add 4
+                instructions.push(0x0E400000); // save ~ 0
+                instructions.append(&mut load_var(scope, capture)?);
+                instructions.push(0x10400000); // svrel ~ 0
+            }
+            scope.spadd(2);
+            instructions.push(0x0D400004); // load ~ 4
+            instructions.push(0x0C800008); // spadd 8
+
+            Ok(instructions)
+        }
+        Expression::Apply { f, args } => {
+            // closure and code address are stored above the arguments
+            let n = args.len() as u32;
+            let mut instructions = vec![0x0C800000 | ((n + 2) as i16 * -4) as u16 as u32]; // spadd -x
+            scope.spadd(-(n as i16 + 2));
+
+            instructions.append(&mut translate(f, scope, data)?);
+            instructions.push(0x0E400000 + 4 * n); // save ~ n - closure
+            instructions.push(0x0F400000 + 4 * n); // ldrel ~ n - code address
+            instructions.push(0x0E400000 + 4 * (n + 1)); // save ~ n+1
+            for (idx, arg) in args.iter().enumerate() {
+                instructions.append(&mut translate(arg, scope, data)?);
+                instructions.push(0x0E400000 + 4 * (n - idx as u32 - 1)); // save ~n
+            }
+            instructions.push(0x0A400000 + 4 * (n + 1)); // call ~ n+1
+            instructions.push(0x0C800000 + 4 * (n + 2)); // spadd x
+
+            scope.spadd(n as i16 + 2);
+
+            Ok(instructions)
+        }
+        Expression::Break | Expression::Continue => {
+            let (form, placeholder) = match expr {
+                Expression::Break => ("break", BREAK),
+                _ => ("continue", CONTINUE),
+            };
+            let depth = scope.loop_depth(form)?;
+
+            Ok(scope
+                .leave_tries(depth)
+                .into_iter()
+                .chain(scope.unwind_to(depth))
+                .chain([placeholder])
+                .collect())
+        }
+        Expression::Return(expr) => {
+            if !scope.in_fn {
+                return Err(CompileError::Misplaced {
+                    form: "return".to_string(),
+                    at: None,
+                });
+            }
+            let mut instructions = translate(expr, scope, data)?;
+            instructions.append(&mut scope.leave_tries(0));
+            instructions.extend(scope.unwind_to(0));
+            instructions.push(0x0B000000); // ret
 
-            instructions
+            Ok(instructions)
         }
-        Expression::Var(name) => {
-            vec![0x0D000000 | vars[name].to_arg()] // load
+        Expression::Try { body, var, handler } => {
+            let (handler_cell, thrown) = scope.exception_cells(data);
+            let mut instructions = vec![
+                0x0C80FFF8,                // spadd -8
+                0x0D000000 | handler_cell, // load # handler
+                0x0E400000,                // save ~ 0 - enclosing record
+                HANDLER_ADDRESS,           // load x, where x is handler address
+                0x0E400004,                // save ~ 4
+                0x12000000,                // ldsp 0
+                0x0E000000 | handler_cell, // save # handler
+            ];
+            scope.spadd(-2);
+
+            scope.tries.push(scope.depth);
+            let mut body_instructions = translate(body, scope, data)?;
+            scope.tries.pop();
+            body_instructions.push(0x0E400004); // save ~ 4
+            body_instructions.push(0x0D400000); // load ~ 0
+            body_instructions.push(0x0E000000 | handler_cell); // save # handler
+            body_instructions.push(0x0D400004); // load ~ 4
+
+            // `throw` has restored the stack and the enclosing record, the
+            // record is free to hold the variable
+            scope.bind(var, Var::Stack(0));
+            let mut handler_instructions = vec![
+                0x0D000000 | thrown, // load # thrown
+                0x0E400000,          // save ~ 0
+            ];
+            handler_instructions.append(&mut translate(handler, scope, data)?);
+            scope.unbind(var);
+
+            instructions[3] |= body_instructions.len() as u32 + 5;
+            body_instructions.push(jump(0x08000000, handler_instructions.len() as i32 + 1)?); // jump -> end
+            instructions.append(&mut body_instructions);
+            instructions.append(&mut handler_instructions);
+            instructions.push(0x0C800008); // spadd 8 :end
+            scope.spadd(2);
+
+            Ok(instructions)
+        }
+        Expression::Throw { code, .. } => {
+            let (handler, thrown) = scope.exception_cells(data);
+            let mut instructions = translate(code, scope, data)?;
+            instructions.extend([
+                0x0E000000 | thrown,   // save # thrown
+                0x0D000000 | handler,  // load # handler
+                jump(0x09000000, 9)?,  // jifz -> uncaught
+                0x12000000,            // ldsp 0
+                0x04000000 | handler,  // sub # handler
+                0x0580FFFF,            // mul -1
+                0x0CC00000,            // spadd acc - back to the record
+                0x0D400000,            // load ~ 0
+                0x0E000000 | handler,  // save # handler - enclosing record
+                0x0D400004,            // load ~ 4
+                0x08C00000,            // jump acc
+                0x11000000 | UNCAUGHT, // halt x :uncaught
+            ]);
+
+            Ok(instructions)
         }
+        Expression::Do(exprs) => {
+            let mut instructions = Vec::new();
+            for expr in exprs {
+                let mut expr_instructions = translate(expr, scope, data)?;
+                instructions.append(&mut expr_instructions);
+            }
+
+            Ok(instructions)
+        }
+        Expression::Var(name) => load_var(scope, name),
         Expression::Str(s) => {
-            let pointer = data.len() + 12;
-            data.append(&mut Vec::from(s.clone().as_bytes()));
+            let pointer = data.len() + STATIC_DATA;
+            data.extend_from_slice(s);
             data.push(0);
 
-            vec![0x0D800000 | pointer as u32] // load
+            Ok(vec![0x0D800000 | pointer as u32]) // load
         }
-        Expression::Value(num) => {
-            if let Ok(num) = i16::try_from(*num) {
-                vec![0x0D000000 | Var::InWord(num as u16).to_arg()] // load
-            } else {
-                let var = Var::Memory(data.len() as u16 + 12);
-                data.append(&mut Vec::from(num.to_le_bytes()));
-
-                vec![0x0D000000 | var.to_arg()] // load
+        Expression::Array(items) => {
+            let pointer = data.len() + STATIC_DATA;
+            data.extend_from_slice(&(items.len() as u32).to_le_bytes());
+            for item in items {
+                data.extend_from_slice(&item.to_le_bytes());
             }
+
+            Ok(vec![0x0D800000 | pointer as u32]) // load
+        }
+        Expression::Quote(Datum::Int(num)) => translate(&Expression::Value(*num), scope, data),
+        Expression::Quote(datum) => {
+            let pointer = quoted(datum, scope, data);
+
+            Ok(vec![0x0D800000 | pointer]) // load
         }
+        Expression::Value(num) => Ok(vec![0x0D000000 | constant(*num, data).to_arg()]), // load
     }
 }
 
-pub fn compile(preprocessed: Preprocessed) -> (Vec<u8>, usize, usize) {
-    let (mut instructions, mut fn_addresses) = built_in();
+pub fn compile(
+    preprocessed: Preprocessed,
+    bounds_check: bool,
+) -> Result<(Vec<u8>, usize, usize), CompileError> {
+    let (mut instructions, mut fn_addresses) = built_in()?;
     let mut data: Vec<u8> = Vec::new();
-    let mut vars = HashMap::new();
+    let mut scope = Scope {
+        bounds_check,
+        ..Scope::new(false)
+    };
+
+    // constant initializers go straight to the static memory, others are
+    // evaluated at startup
+    let mut initializers = Vec::new();
+    for global in preprocessed.globals {
+        if let Expression::Def { name, init } = global {
+            let var = Var::Memory((data.len() + STATIC_DATA) as u16);
+            match *init {
+                Expression::Value(num) => data.extend_from_slice(&num.to_le_bytes()),
+                init => {
+                    data.extend_from_slice(&[0; 4]);
+                    initializers.push((name.clone(), init));
+                }
+            }
+            scope.globals.insert(name, var);
+        }
+    }
 
     for fn_def in preprocessed.fn_defs {
         if let Expression::FnDef { name, .. } = &fn_def {
             fn_addresses.insert(name.clone(), instructions.len() as u16);
-        } else {
-            panic!("There must be function definition");
         }
-        let mut fn_def_asm = translate(&fn_def, &mut vars, &fn_addresses, &mut data);
+        let mut fn_def_asm = translate(&fn_def, &mut scope, &mut data)?;
         instructions.append(&mut fn_def_asm);
     }
-    instructions[0] = 0x08000000 + instructions.len() as u32; // jump n
+    instructions[0] = jump(0x08000000, instructions.len() as i32)?; // jump n
 
+    for (name, init) in initializers {
+        let mut init_instructions = translate(&init, &mut scope, &mut data)?;
+        instructions.append(&mut init_instructions);
+        instructions.append(&mut save_var(&mut scope, &name)?);
+    }
     for expr in preprocessed.main {
-        let mut expr_instructions = translate(&expr, &mut vars, &fn_addresses, &mut data);
+        let mut expr_instructions = translate(&expr, &mut scope, &mut data)?;
         instructions.append(&mut expr_instructions);
     }
     instructions.push(0x11000000); // halt
+    patch_addresses(&mut instructions, &mut data, &scope, &fn_addresses)?;
+    if instructions.len() > MAX_ADDRESS {
+        return Err(CompileError::BackendLimit {
+            limit: Limit::Instructions,
+            size: instructions.len(),
+            max: MAX_ADDRESS,
+            at: None,
+        });
+    }
 
     eprintln!("Instructions:");
     let instructions_count = instructions.len();
@@ -220,7 +1079,15 @@ pub fn compile(preprocessed: Preprocessed) -> (Vec<u8>, usize, usize) {
         );
     }
 
-    let mut data = [vec![0u8; 12], data].concat();
+    let mut data = [vec![0u8; STATIC_DATA], data].concat();
+    if data.len() > MAX_ADDRESS {
+        return Err(CompileError::BackendLimit {
+            limit: Limit::Data,
+            size: data.len(),
+            max: MAX_ADDRESS,
+            at: None,
+        });
+    }
     data[8] = (data.len() as u32).to_le_bytes()[0];
     data[9] = (data.len() as u32).to_le_bytes()[1];
     data[10] = (data.len() as u32).to_le_bytes()[2];
@@ -241,5 +1108,5 @@ pub fn compile(preprocessed: Preprocessed) -> (Vec<u8>, usize, usize) {
     .concat();
     let bytes_count = bytecode.len();
 
-    (bytecode, instructions_count, bytes_count)
+    Ok((bytecode, instructions_count, bytes_count))
 }
@@ -0,0 +1,365 @@
+use std::{error::Error, fmt};
+
+use crate::util::{expression::ExpressionType, source::Span, types::Type};
+
+/// Where an error happened: file name, span and the rendered source excerpt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub span: Span,
+    pub excerpt: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LiteralKind {
+    Char,
+    Number,
+    Overflow,
+    Str,
+    Escape,
+    Instruction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Instructions,
+    Data,
+    JumpDistance,
+}
+
+/// How the expected number of arguments limits the given one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bound {
+    Exactly,
+    AtLeast,
+    AtMost,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportProblem {
+    NotFound,
+    /// File names of the modules in the cycle, starting and ending with the
+    /// same one.
+    Cycle(Vec<String>),
+    Namespace,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    Syntax {
+        expected: ExpressionType,
+        at: Option<Location>,
+    },
+    MalformedLiteral {
+        kind: LiteralKind,
+        at: Option<Location>,
+    },
+    UnknownName {
+        name: String,
+        at: Option<Location>,
+    },
+    Redeclaration {
+        name: String,
+        at: Option<Location>,
+    },
+    Arity {
+        name: String,
+        expected: usize,
+        bound: Bound,
+        found: usize,
+        at: Option<Location>,
+    },
+    BackendLimit {
+        limit: Limit,
+        size: usize,
+        max: usize,
+        at: Option<Location>,
+    },
+    Misplaced {
+        form: String,
+        at: Option<Location>,
+    },
+    MacroExpansion {
+        name: String,
+        at: Option<Location>,
+    },
+    Import {
+        path: Box<str>,
+        problem: ImportProblem,
+        at: Option<Location>,
+    },
+    /// A value of the type `found` where `target` requires `expected`.
+    TypeMismatch {
+        target: String,
+        expected: Type,
+        found: Type,
+        at: Option<Location>,
+    },
+}
+
+impl CompileError {
+    /// Stable identifier of the error kind. Codes are never reused.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Syntax { .. } => "E0001",
+            Self::MalformedLiteral { .. } => "E0002",
+            Self::UnknownName { .. } => "E0003",
+            Self::Redeclaration { .. } => "E0004",
+            Self::Arity { .. } => "E0005",
+            Self::BackendLimit { .. } => "E0006",
+            Self::Misplaced { .. } => "E0007",
+            Self::MacroExpansion { .. } => "E0008",
+            Self::Import { .. } => "E0009",
+            Self::TypeMismatch { .. } => "E0010",
+        }
+    }
+
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Self::Syntax { at, .. }
+            | Self::MalformedLiteral { at, .. }
+            | Self::UnknownName { at, .. }
+            | Self::Redeclaration { at, .. }
+            | Self::Arity { at, .. }
+            | Self::BackendLimit { at, .. }
+            | Self::Misplaced { at, .. }
+            | Self::MacroExpansion { at, .. }
+            | Self::Import { at, .. }
+            | Self::TypeMismatch { at, .. } => at.as_ref(),
+        }
+    }
+
+    /// Attaches location to the error if it does not have one yet.
+    pub fn at(mut self, location: Location) -> Self {
+        match &mut self {
+            Self::Syntax { at, .. }
+            | Self::MalformedLiteral { at, .. }
+            | Self::UnknownName { at, .. }
+            | Self::Redeclaration { at, .. }
+            | Self::Arity { at, .. }
+            | Self::BackendLimit { at, .. }
+            | Self::Misplaced { at, .. }
+            | Self::MacroExpansion { at, .. }
+            | Self::Import { at, .. }
+            | Self::TypeMismatch { at, .. } => {
+                at.get_or_insert(location);
+            }
+        }
+
+        self
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Self::Syntax { expected, .. } => expected.description().to_string(),
+            Self::MalformedLiteral { kind, .. } => match kind {
+                LiteralKind::Char => "A character was expected here".to_string(),
+                LiteralKind::Number => "A number was expected here".to_string(),
+                LiteralKind::Overflow => "Integer literal does not fit in 32 bits".to_string(),
+                LiteralKind::Str => "Unterminated string literal".to_string(),
+                LiteralKind::Escape => "Unknown escape sequence".to_string(),
+                LiteralKind::Instruction => "An instruction was expected here".to_string(),
+            },
+            Self::UnknownName { name, .. } => format!("Name '{}' is not declared", name),
+            Self::Redeclaration { name, .. } => {
+                format!("Variable or function name '{}' is already declared", name)
+            }
+            Self::Arity {
+                name,
+                expected,
+                bound,
+                found,
+                ..
+            } => {
+                let bound = match bound {
+                    Bound::Exactly => "",
+                    Bound::AtLeast => "at least ",
+                    Bound::AtMost => "at most ",
+                };
+                format!(
+                    "Function '{}' takes {}{} arguments but {} were given",
+                    name, bound, expected, found
+                )
+            }
+            Self::BackendLimit {
+                limit, size, max, ..
+            } => match limit {
+                Limit::Instructions => format!("Too many instructions: {} of {}", size, max),
+                Limit::Data => format!("Too much static data: {} of {} bytes", size, max),
+                Limit::JumpDistance => {
+                    format!("Jump is too long: {} of {} instructions", size, max)
+                }
+            },
+            Self::Misplaced { form, .. } => match form.as_str() {
+                "return" => "'return' is only allowed inside a function".to_string(),
+                "def" | "defmacro" | "import" | "struct" => {
+                    format!("'{}' is only allowed at the top level", form)
+                }
+                _ => format!("'{}' is only allowed inside a loop", form),
+            },
+            Self::MacroExpansion { name, .. } => {
+                format!("Macro '{}' is expanded too many times", name)
+            }
+            Self::Import { path, problem, .. } => match problem {
+                ImportProblem::NotFound => format!("Module '{}' is not found", path),
+                ImportProblem::Cycle(chain) => format!(
+                    "Import cycle through module '{}': {}",
+                    path,
+                    chain.join(" -> ")
+                ),
+                ImportProblem::Namespace => format!(
+                    "Module '{}' is already imported under another namespace",
+                    path
+                ),
+            },
+            Self::TypeMismatch {
+                target,
+                expected,
+                found,
+                ..
+            } => format!("Expected {} for {}, found {}", expected, target, found),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.location() {
+            Some(at) => write!(
+                f,
+                "{}:{}:{}: error[{}]: {}\n{}",
+                at.file,
+                at.span.line,
+                at.span.col,
+                self.code(),
+                self.message(),
+                at.excerpt
+            ),
+            None => write!(f, "error[{}]: {}", self.code(), self.message()),
+        }
+    }
+}
+
+impl Error for CompileError {}
+
+/// Suspicious code that still compiles. Warnings are opt-in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    Shadowing { name: String, at: Location },
+}
+
+impl Warning {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Shadowing { .. } => "W0001",
+        }
+    }
+
+    pub fn location(&self) -> &Location {
+        match self {
+            Self::Shadowing { at, .. } => at,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Self::Shadowing { name, .. } => {
+                format!("Variable '{}' shadows an outer variable or function", name)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = self.location();
+        write!(
+            f,
+            "{}:{}:{}: warning[{}]: {}\n{}",
+            at.file,
+            at.span.line,
+            at.span.col,
+            self.code(),
+            self.message(),
+            at.excerpt
+        )
+    }
+}
+
+pub const MAX_ERRORS: usize = 20;
+
+/// Errors and warnings collected while compiling a file, reported together.
+#[derive(Debug)]
+pub struct Diagnostics {
+    pub errors: Vec<CompileError>,
+    pub warnings: Vec<Warning>,
+    pub max_errors: usize,
+    pub warn_shadowing: bool,
+}
+
+impl Diagnostics {
+    pub fn new(max_errors: usize) -> Self {
+        Diagnostics {
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            max_errors,
+            warn_shadowing: false,
+        }
+    }
+
+    /// Records the error unless the cap is already reached.
+    pub fn report(&mut self, error: CompileError) {
+        if !self.is_full() {
+            self.errors.push(error);
+        }
+    }
+
+    /// Records the warning if warnings of its kind are enabled.
+    pub fn warn(&mut self, warning: Warning) {
+        let enabled = match warning {
+            Warning::Shadowing { .. } => self.warn_shadowing,
+        };
+        if enabled {
+            self.warnings.push(warning);
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.errors.len() >= self.max_errors
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl From<CompileError> for Diagnostics {
+    fn from(error: CompileError) -> Self {
+        Diagnostics {
+            errors: vec![error],
+            ..Diagnostics::new(1)
+        }
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for warning in &self.warnings {
+            writeln!(f, "{}\n", warning)?;
+        }
+        for error in &self.errors {
+            writeln!(f, "{}\n", error)?;
+        }
+        if self.is_full() && self.max_errors > 1 {
+            writeln!(f, "Too many errors, stopped after {}", self.max_errors)?;
+        }
+        write!(
+            f,
+            "Compilation failed: {} error{}",
+            self.errors.len(),
+            if self.errors.len() == 1 { "" } else { "s" }
+        )
+    }
+}
+
+impl Error for Diagnostics {}
@@ -0,0 +1,618 @@
+use crate::{
+    error::{CompileError, Diagnostics, LiteralKind, Location},
+    util::source::{Source, Span},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Open,
+    Close,
+    OpenBracket,
+    CloseBracket,
+    /// `'` before a list or a name, as in `'(1 2 3)` and `'abc`.
+    Quote,
+    /// `` ` ``, `,` and `,@` in macro templates.
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
+    Char(u8),
+    Str(Vec<u8>),
+    Atom(String),
+    /// A malformed literal that has already been reported by the lexer.
+    Invalid,
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+struct Lexer<'a> {
+    source: &'a Source,
+    chars: Vec<(usize, char)>,
+    pos: usize,
+    line: usize,
+    col: usize,
+    errors: Vec<CompileError>,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(source: &'a Source) -> Self {
+        Lexer {
+            source,
+            chars: source.text.char_indices().collect(),
+            pos: 0,
+            line: 1,
+            col: 1,
+            errors: Vec::new(),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).map(|v| v.1)
+    }
+
+    fn lookahead(&self, n: usize) -> Option<char> {
+        self.chars.get(self.pos + n).map(|v| v.1)
+    }
+
+    /// Whether the `'` at the cursor quotes a name, as in `'abc`: a name
+    /// follows it and no `'` closes it into a character literal.
+    fn quotes_name(&self) -> bool {
+        let mut n = 1;
+        while let Some(chr) = self.lookahead(n) {
+            if chr.is_whitespace() || matches!(chr, '(' | ')' | '[' | ']' | ';' | '"' | '`' | ',') {
+                break;
+            }
+            if matches!(chr, '\'' | '\\') {
+                return false;
+            }
+            n += 1;
+        }
+
+        n > 1
+    }
+
+    fn offset(&self) -> usize {
+        self.chars
+            .get(self.pos)
+            .map(|v| v.0)
+            .unwrap_or(self.source.text.len())
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let chr = self.peek()?;
+        self.pos += 1;
+        match chr {
+            '\r' if self.peek() == Some('\n') => (),
+            '\n' => {
+                self.line += 1;
+                self.col = 1;
+            }
+            _ => self.col += 1,
+        }
+
+        Some(chr)
+    }
+
+    fn span_from(&self, start: Span) -> Span {
+        Span {
+            end: self.offset(),
+            ..start
+        }
+    }
+
+    fn here(&self) -> Span {
+        Span {
+            start: self.offset(),
+            end: self.offset(),
+            line: self.line,
+            col: self.col,
+        }
+    }
+
+    fn skip_blank(&mut self) {
+        while let Some(chr) = self.peek() {
+            if chr == ';' {
+                while !matches!(self.peek(), None | Some('\n')) {
+                    self.bump();
+                }
+            } else if chr.is_whitespace() {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn malformed(&self, kind: LiteralKind, start: Span) -> CompileError {
+        CompileError::MalformedLiteral {
+            kind,
+            at: Some(self.source.locate(self.span_from(start))),
+        }
+    }
+
+    /// Decodes an escape sequence starting at `\`: `\n`, `\t`, `\0`, `\\`,
+    /// `\"`, `\'` or `\xNN`.
+    fn escape(&mut self) -> Result<u8, CompileError> {
+        let start = self.here();
+        self.bump();
+        let byte = match self.bump() {
+            Some('n') => Some(b'\n'),
+            Some('t') => Some(b'\t'),
+            Some('0') => Some(0),
+            Some('\\') => Some(b'\\'),
+            Some('"') => Some(b'"'),
+            Some('\'') => Some(b'\''),
+            Some('x') => {
+                let digits: String = (0..2)
+                    .map_while(|_| {
+                        let digit = self.peek().filter(|v| v.is_ascii_hexdigit())?;
+                        self.bump();
+                        Some(digit)
+                    })
+                    .collect();
+                u8::from_str_radix(&digits, 16)
+                    .ok()
+                    .filter(|_| digits.len() == 2)
+            }
+            _ => None,
+        };
+
+        byte.ok_or_else(|| self.malformed(LiteralKind::Escape, start))
+    }
+
+    fn str_literal(&mut self, start: Span) -> Result<Vec<u8>, CompileError> {
+        self.bump();
+        let mut content = Vec::new();
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.bump();
+                    return Ok(content);
+                }
+                Some('\\') => match self.escape() {
+                    Ok(byte) => content.push(byte),
+                    Err(e) => self.errors.push(e),
+                },
+                Some(chr) => {
+                    self.bump();
+                    content.extend_from_slice(chr.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                None => return Err(self.malformed(LiteralKind::Str, start)),
+            }
+        }
+    }
+
+    fn char_literal(&mut self, start: Span) -> TokenKind {
+        self.bump();
+        let chr = match self.peek() {
+            Some('\\') => self.escape(),
+            Some(chr) if chr.is_ascii() => {
+                self.bump();
+                Ok(chr as u8)
+            }
+            _ => Err(self.malformed(LiteralKind::Char, start)),
+        };
+        if let (Ok(chr), Some('\'')) = (&chr, self.peek()) {
+            self.bump();
+            return TokenKind::Char(*chr);
+        }
+
+        while let Some(chr) = self.peek() {
+            if chr.is_whitespace() || matches!(chr, '(' | ')') {
+                break;
+            }
+            self.bump();
+            if chr == '\'' {
+                break;
+            }
+        }
+        let error = chr
+            .err()
+            .unwrap_or_else(|| self.malformed(LiteralKind::Char, start));
+        self.errors.push(error);
+
+        TokenKind::Invalid
+    }
+
+    fn atom(&mut self) -> String {
+        let mut atom = String::new();
+        while let Some(chr) = self.peek() {
+            if chr.is_whitespace() || matches!(chr, '(' | ')' | '[' | ']' | ';' | '"' | '`' | ',') {
+                break;
+            }
+            atom.push(chr);
+            self.bump();
+        }
+
+        atom
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>, CompileError> {
+        self.skip_blank();
+        let start = self.here();
+        let kind = match self.peek() {
+            None => return Ok(None),
+            Some('(') => {
+                self.bump();
+                TokenKind::Open
+            }
+            Some(')') => {
+                self.bump();
+                TokenKind::Close
+            }
+            Some('[') => {
+                self.bump();
+                TokenKind::OpenBracket
+            }
+            Some(']') => {
+                self.bump();
+                TokenKind::CloseBracket
+            }
+            // `'('` is still a character
+            Some('\'')
+                if (self.lookahead(1) == Some('(') && self.lookahead(2) != Some('\''))
+                    || self.quotes_name() =>
+            {
+                self.bump();
+                TokenKind::Quote
+            }
+            Some('\'') => self.char_literal(start),
+            Some('`') => {
+                self.bump();
+                TokenKind::Quasiquote
+            }
+            Some(',') => {
+                self.bump();
+                if self.peek() == Some('@') {
+                    self.bump();
+                    TokenKind::UnquoteSplicing
+                } else {
+                    TokenKind::Unquote
+                }
+            }
+            Some('"') => TokenKind::Str(self.str_literal(start)?),
+            Some(_) => TokenKind::Atom(self.atom()),
+        };
+
+        Ok(Some(Token {
+            kind,
+            span: self.span_from(start),
+        }))
+    }
+}
+
+/// Splits source into tokens. Malformed char literals are reported and lexing
+/// goes on, while an unterminated string swallows the rest of the file and
+/// stops it.
+pub fn tokenize(source: &Source) -> Result<(Vec<Token>, Vec<CompileError>), Vec<CompileError>> {
+    let mut lexer = Lexer::new(source);
+    let mut tokens = Vec::new();
+    loop {
+        match lexer.next_token() {
+            Ok(Some(token)) => tokens.push(token),
+            Ok(None) => return Ok((tokens, lexer.errors)),
+            Err(e) => {
+                lexer.errors.push(e);
+                return Err(lexer.errors);
+            }
+        }
+    }
+}
+
+pub struct Tokens<'a> {
+    pub source: &'a Source,
+    tokens: Vec<Token>,
+    pos: usize,
+    pub diagnostics: Diagnostics,
+    lexer_errors: Vec<CompileError>,
+    /// Number of macro calls expanded so far.
+    pub expansions: usize,
+}
+
+impl<'a> Tokens<'a> {
+    pub fn new(source: &'a Source, mut diagnostics: Diagnostics) -> Result<Self, Diagnostics> {
+        let (tokens, lexer_errors) = match tokenize(source) {
+            Ok(v) => v,
+            Err(errors) => {
+                errors.into_iter().for_each(|v| diagnostics.report(v));
+                return Err(diagnostics);
+            }
+        };
+
+        Ok(Tokens {
+            source,
+            tokens,
+            pos: 0,
+            diagnostics,
+            lexer_errors,
+            expansions: 0,
+        })
+    }
+
+    /// Merges lexer errors into the parser ones and orders them by position,
+    /// keeping at most `max_errors` first errors of the file.
+    pub fn finish(self) -> Diagnostics {
+        let mut diagnostics = self.diagnostics;
+        diagnostics.errors.extend(self.lexer_errors);
+        diagnostics
+            .errors
+            .sort_by_key(|v| v.location().map(|v| v.span.start));
+        diagnostics.errors.truncate(diagnostics.max_errors);
+
+        diagnostics
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Moves past the `)` that matches the `(` at position `open`. Returns
+    /// false and stays in place if the parenthesis is never closed.
+    pub fn skip_to_matching(&mut self, open: usize) -> bool {
+        let mut depth = 0;
+        for (idx, token) in self.tokens.iter().enumerate().skip(open) {
+            match token.kind {
+                TokenKind::Open => depth += 1,
+                TokenKind::Close => depth -= 1,
+                _ => (),
+            }
+            if depth == 0 {
+                if idx < self.pos {
+                    return false;
+                }
+                self.pos = idx + 1;
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Moves past the top-level expression starting at `start` that failed
+    /// to parse: to the first token after it where the parentheses balance
+    /// again. If it is never closed, everything after it was parsed as nested
+    /// in it, and the next `(` that begins a line in the column of the
+    /// expression is taken as the next top-level one.
+    pub fn skip_to_top_level(&mut self, start: usize) {
+        let mut depth = 0;
+        for (idx, token) in self.tokens.iter().enumerate().skip(start) {
+            if depth <= 0 && idx > start && idx >= self.pos {
+                self.pos = idx;
+                return;
+            }
+            match token.kind {
+                TokenKind::Open => depth += 1,
+                TokenKind::Close => depth -= 1,
+                _ => (),
+            }
+        }
+        if depth <= 0 {
+            self.pos = self.tokens.len();
+            return;
+        }
+
+        let col = self.tokens[start].span.col;
+        self.pos = self.pos.max(start + 1);
+        while let Some(token) = self.peek() {
+            let line_start = self.tokens[self.pos - 1].span.line < token.span.line;
+            if token.kind == TokenKind::Open && token.span.col == col && line_start {
+                break;
+            }
+            self.pos += 1;
+        }
+    }
+
+    pub fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    /// Token `n` positions after the next one.
+    pub fn peek_at(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + n)
+    }
+
+    /// Takes one datum: a single token or a parenthesized group, with any
+    /// quote or unquote prefixes. Returns None if a group is never closed or
+    /// nothing but `)` is left.
+    pub fn take_group(&mut self) -> Option<Vec<Token>> {
+        let start = self.pos;
+        let mut depth = 0;
+        for (idx, token) in self.tokens.iter().enumerate().skip(start) {
+            match token.kind {
+                TokenKind::Open => depth += 1,
+                TokenKind::Close if depth == 0 => return None,
+                TokenKind::Close => depth -= 1,
+                TokenKind::Quote
+                | TokenKind::Quasiquote
+                | TokenKind::Unquote
+                | TokenKind::UnquoteSplicing => continue,
+                _ => (),
+            }
+            if depth == 0 {
+                self.pos = idx + 1;
+                return Some(self.tokens[start..self.pos].to_vec());
+            }
+        }
+
+        None
+    }
+
+    /// Replaces the tokens from `start` up to the current position with
+    /// `tokens` and moves back to `start`, so that they are parsed next.
+    pub fn splice(&mut self, start: usize, tokens: Vec<Token>) {
+        self.tokens.splice(start..self.pos, tokens);
+        self.pos = start;
+    }
+
+    pub fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Span of the next token, or an empty span at the end of input.
+    pub fn span(&self) -> Span {
+        self.peek()
+            .map(|v| v.span)
+            .unwrap_or_else(|| self.source.end())
+    }
+
+    pub fn locate(&self, span: Span) -> Location {
+        self.source.locate(span)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tokenize, TokenKind};
+    use crate::util::source::Source;
+
+    fn source(text: &str) -> Source {
+        Source::new("test.nl", text)
+    }
+
+    #[test]
+    fn spans() {
+        let src = source("(print\r\n\t\"a b\") ; comment\n'x'[1]");
+        let (tokens, _) = tokenize(&src).unwrap();
+        let kinds: Vec<TokenKind> = tokens.iter().map(|v| v.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Open,
+                TokenKind::Atom("print".to_string()),
+                TokenKind::Str(b"a b".to_vec()),
+                TokenKind::Close,
+                TokenKind::Char(b'x'),
+                TokenKind::OpenBracket,
+                TokenKind::Atom("1".to_string()),
+                TokenKind::CloseBracket,
+            ]
+        );
+        let positions: Vec<(usize, usize)> =
+            tokens.iter().map(|v| (v.span.line, v.span.col)).collect();
+        assert_eq!(
+            positions,
+            vec![
+                (1, 1),
+                (1, 2),
+                (2, 2),
+                (2, 7),
+                (3, 1),
+                (3, 4),
+                (3, 5),
+                (3, 6)
+            ]
+        );
+        assert_eq!(
+            &src.text[tokens[2].span.start..tokens[2].span.end],
+            "\"a b\""
+        );
+    }
+
+    #[test]
+    fn unterminated() {
+        let src = source("(print\n  \"abc)");
+        let err = tokenize(&src).unwrap_err();
+        assert_eq!(
+            err[0].to_string(),
+            "test.nl:2:3: error[E0002]: Unterminated string literal\n  2 |   \"abc)\n    |   ^^^^^"
+        );
+    }
+
+    #[test]
+    fn bad_char() {
+        let src = source("(out 'ab') (out 'é')");
+        let (tokens, err) = tokenize(&src).unwrap();
+        assert_eq!(tokens[2].kind, TokenKind::Invalid);
+        assert_eq!(tokens[6].kind, TokenKind::Invalid);
+        assert_eq!(tokens.len(), 8);
+        assert_eq!(
+            err[0].to_string(),
+            "test.nl:1:6: error[E0002]: A character was expected here\n  1 | (out 'ab') (out 'é')\n    |      ^^^^"
+        );
+    }
+
+    #[test]
+    fn quote() {
+        let src = source("'(a) '(' '() 'abc 'x' 'c)");
+        let (tokens, errors) = tokenize(&src).unwrap();
+        assert!(errors.is_empty());
+        let kinds: Vec<TokenKind> = tokens.into_iter().map(|v| v.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Quote,
+                TokenKind::Open,
+                TokenKind::Atom("a".to_string()),
+                TokenKind::Close,
+                TokenKind::Char(b'('),
+                TokenKind::Quote,
+                TokenKind::Open,
+                TokenKind::Close,
+                TokenKind::Quote,
+                TokenKind::Atom("abc".to_string()),
+                TokenKind::Char(b'x'),
+                TokenKind::Quote,
+                TokenKind::Atom("c".to_string()),
+                TokenKind::Close,
+            ]
+        );
+    }
+
+    #[test]
+    fn quasiquote() {
+        let src = source("`(a,b ,@c)");
+        let (tokens, _) = tokenize(&src).unwrap();
+        let kinds: Vec<TokenKind> = tokens.into_iter().map(|v| v.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Quasiquote,
+                TokenKind::Open,
+                TokenKind::Atom("a".to_string()),
+                TokenKind::Unquote,
+                TokenKind::Atom("b".to_string()),
+                TokenKind::UnquoteSplicing,
+                TokenKind::Atom("c".to_string()),
+                TokenKind::Close,
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        let src = source(r#"("a\n\t\"\\\x41\0" '\'' '\x7f' '\n')"#);
+        let (tokens, errors) = tokenize(&src).unwrap();
+        assert!(errors.is_empty());
+        let kinds: Vec<TokenKind> = tokens.into_iter().map(|v| v.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Open,
+                TokenKind::Str(b"a\n\t\"\\A\0".to_vec()),
+                TokenKind::Char(b'\''),
+                TokenKind::Char(0x7f),
+                TokenKind::Char(b'\n'),
+                TokenKind::Close,
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_escapes() {
+        let src = source(r#"("a\q" '\x4' '\xZZ')"#);
+        let (tokens, errors) = tokenize(&src).unwrap();
+        assert_eq!(tokens[1].kind, TokenKind::Str(b"a".to_vec()));
+        assert_eq!(tokens[2].kind, TokenKind::Invalid);
+        assert_eq!(tokens[3].kind, TokenKind::Invalid);
+        let cols: Vec<usize> = errors
+            .iter()
+            .map(|v| v.location().unwrap().span.col)
+            .collect();
+        assert_eq!(cols, vec![4, 9, 15]);
+    }
+}
@@ -1,4 +1,7 @@
+mod checker;
 mod compiler;
+mod error;
+mod lexer;
 mod parser;
 mod preprocess;
 mod util;
@@ -8,46 +11,101 @@ use std::{
     error::Error,
     fs::File,
     io::{Read, Write},
+    path::{Path, PathBuf},
+    process,
 };
 
+use checker::check;
 use compiler::compile;
-use parser::parse;
+use error::{Diagnostics, MAX_ERRORS};
 use preprocess::{preprocess, Preprocessed};
-use util::std_expr::parse_std;
+use util::{modules::Modules, source::Source, std_expr::parse_std};
 
-fn parse_args() -> Result<(File, File), String> {
+struct Args {
+    input_name: String,
+    input: File,
+    output: File,
+    max_errors: usize,
+    warn_shadowing: bool,
+    bounds_check: bool,
+    import_paths: Vec<PathBuf>,
+}
+
+fn parse_args() -> Result<Args, String> {
     let args: Vec<_> = env::args().collect();
     if args.len() < 3 {
         return Err("Not enough arguments".to_string());
     }
 
+    let mut max_errors = MAX_ERRORS;
+    let mut warn_shadowing = false;
+    let mut bounds_check = false;
+    let mut import_paths = Vec::new();
+    for option in &args[3..] {
+        match option.as_str() {
+            "--warn-shadowing" => warn_shadowing = true,
+            "--bounds-check" => bounds_check = true,
+            _ if option.starts_with("--import-path=") => {
+                import_paths.push(PathBuf::from(&option["--import-path=".len()..]))
+            }
+            _ => {
+                max_errors = option
+                    .strip_prefix("--max-errors=")
+                    .and_then(|v| v.parse().ok())
+                    .filter(|v| *v > 0)
+                    .ok_or_else(|| format!("Unknown option '{}'", option))?
+            }
+        }
+    }
+
     let input = File::open(&args[1]).map_err(|_| "Can't open input file".to_string())?;
     let output = File::create(&args[2]).unwrap();
 
-    Ok((input, output))
+    Ok(Args {
+        input_name: args[1].clone(),
+        input,
+        output,
+        max_errors,
+        warn_shadowing,
+        bounds_check,
+        import_paths,
+    })
 }
 
-fn preprocessed_expressions(input_str: String) -> Result<Preprocessed, Box<dyn Error>> {
-    let (mut std_expressions, mut std_declared) = parse_std();
-    let expressions = parse(input_str, &mut std_declared)?;
-    let mut preprocessed = preprocess(expressions);
+fn preprocessed_expressions(
+    source: &Source,
+    mut modules: Modules,
+) -> Result<Preprocessed, Diagnostics> {
+    let (mut std_expressions, mut std_declared) = parse_std()?;
+    modules.load(Path::new(&source.name), source, None, &mut std_declared)?;
+    for warning in modules.warnings {
+        eprintln!("{}\n", warning);
+    }
+    check(
+        std_expressions.iter().chain(&modules.expressions),
+        &std_declared,
+        Diagnostics::new(modules.max_errors),
+    )?;
+    let mut preprocessed = preprocess(modules.expressions);
     std_expressions.append(&mut preprocessed.fn_defs);
     preprocessed.fn_defs = std_expressions;
 
     Ok(preprocessed)
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let (mut input, mut output) = parse_args()?;
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut args = parse_args()?;
 
     let mut input_str = String::new();
-    input.read_to_string(&mut input_str)?;
+    args.input.read_to_string(&mut input_str)?;
     let code_lines_count = input_str.lines().count();
 
-    let preprocessed = preprocessed_expressions(input_str)?;
-    let (bytecode, instructions_count, bytes_count) = compile(preprocessed);
+    let source = Source::new(&args.input_name, &input_str);
+    let modules = Modules::new(args.import_paths, args.max_errors, args.warn_shadowing);
+    let preprocessed = preprocessed_expressions(&source, modules)?;
+    let (bytecode, instructions_count, bytes_count) = compile(preprocessed, args.bounds_check)?;
 
-    output.write_all(&bytecode)?;
+    args.output.write_all(&bytecode)?;
     eprintln!(
         "Code lines: {}; instructions: {}; bytes: {}",
         code_lines_count, instructions_count, bytes_count
@@ -55,3 +113,10 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{e}");
+        process::exit(1);
+    }
+}
@@ -1,216 +1,2043 @@
+use std::collections::HashSet;
+
 use crate::{
-    util::expression::{Expression, ExpressionType},
-    util::{declared::Declared, error_code, replace_n, split_first},
+    error::{Bound, CompileError, Diagnostics, LiteralKind, Location, Warning},
+    lexer::{tokenize, Token, TokenKind, Tokens},
+    util::expression::{Datum, Expression, ExpressionType},
+    util::{
+        declared::{Accessor, Declared, Rest, Signature},
+        macros::MacroRule,
+        source::{Source, Span},
+        types::{annotated, Type},
+    },
 };
 
-fn parse_char(expr_pointer: &mut &str) -> Result<Expression, String> {
-    *expr_pointer = expr_pointer.trim_start();
-    if expr_pointer.as_bytes()[0] != b'\'' || expr_pointer.as_bytes()[2] != b'\'' {
-        return Err(ExpressionType::Char.to_explained_string(expr_pointer));
+/// Guards against macros that expand into their own calls forever.
+const MAX_EXPANSIONS: usize = 1000;
+
+fn expect_atom(
+    tokens: &mut Tokens,
+    expr_type: ExpressionType,
+) -> Result<(String, Span), CompileError> {
+    let span = tokens.span();
+    match tokens.next() {
+        Some(Token {
+            kind: TokenKind::Atom(atom),
+            span,
+        }) => Ok((atom, span)),
+        _ => Err(expr_type.to_error(tokens.source, span)),
     }
+}
 
-    let chr = expr_pointer.as_bytes()[1];
-    *expr_pointer = &expr_pointer[3..];
+/// Reads a name that may be annotated with a type as `name:type`.
+fn expect_annotated(
+    tokens: &mut Tokens,
+    expr_type: ExpressionType,
+) -> Result<(String, Option<Type>, Span), CompileError> {
+    let (atom, span) = expect_atom(tokens, expr_type)?;
+    match annotated(&atom) {
+        Some((name, ty)) => Ok((name.to_string(), ty, span)),
+        None => Err(ExpressionType::Type.to_error(tokens.source, span)),
+    }
+}
 
-    Ok(Expression::Value(chr.into()))
+/// Takes the `)`. Any other token is left in place, since it may begin the
+/// next top-level expression, from which the recovery goes on.
+fn expect_close(tokens: &mut Tokens) -> Result<(), CompileError> {
+    let span = tokens.span();
+    match tokens.peek().map(|v| &v.kind) {
+        Some(TokenKind::Close) => {
+            tokens.next();
+            Ok(())
+        }
+        _ => Err(ExpressionType::Close.to_error(tokens.source, span)),
+    }
 }
 
-fn parse_num(expr_pointer: &mut &str) -> Result<Expression, String> {
-    *expr_pointer = expr_pointer.trim_start();
-    let (num, other) = split_first(expr_pointer, &[' ', ')'], ExpressionType::Number)?;
-    let num = num
-        .parse::<i32>()
-        .map_err(|_| ExpressionType::Number.to_explained_string(expr_pointer))?;
-    *expr_pointer = other;
+fn malformed(tokens: &Tokens, kind: LiteralKind, span: Span) -> CompileError {
+    CompileError::MalformedLiteral {
+        kind,
+        at: Some(tokens.locate(span)),
+    }
+}
+
+/// Reports a failed declaration without stopping the parser, since the name
+/// conflict does not break the structure of the code around it.
+fn declare(tokens: &mut Tokens, result: Result<(), CompileError>, span: Span) {
+    if let Err(e) = result {
+        let error = e.at(tokens.locate(span));
+        tokens.diagnostics.report(error);
+    }
+}
 
-    Ok(Expression::Value(num))
+/// Declares a variable, warning if it shadows another variable or a function.
+fn declare_var(tokens: &mut Tokens, declared: &mut Declared, name: &str, span: Span) {
+    if declared.var_dec(name) {
+        let warning = Warning::Shadowing {
+            name: name.to_string(),
+            at: tokens.locate(span),
+        };
+        tokens.diagnostics.warn(warning);
+    }
 }
 
-fn parse_str(expr_pointer: &mut &str) -> Result<Expression, String> {
-    *expr_pointer = expr_pointer.trim_start();
-    if expr_pointer.as_bytes()[0] != b'\"' {
-        return Err(ExpressionType::Literal.to_explained_string(expr_pointer));
+fn parse_char(tokens: &mut Tokens) -> Result<Expression, CompileError> {
+    let span = tokens.span();
+    match tokens.next().map(|v| v.kind) {
+        Some(TokenKind::Char(chr)) => Ok(Expression::Value(chr.into())),
+        Some(TokenKind::Invalid) => Ok(Expression::Value(0)),
+        _ => {
+            let error = malformed(tokens, LiteralKind::Char, span);
+            tokens.diagnostics.report(error);
+            Ok(Expression::Value(0))
+        }
     }
+}
 
-    let (literal, other) = split_first(&expr_pointer[1..], &['\"'], ExpressionType::Literal)?;
-    *expr_pointer = &other[1..];
+/// Numbers start with a digit, optionally after a minus sign; any other atom is
+/// a name.
+fn is_number(atom: &str) -> bool {
+    atom.strip_prefix('-')
+        .unwrap_or(atom)
+        .starts_with(|v: char| v.is_ascii_digit())
+}
 
-    Ok(Expression::Str(literal.to_string()))
+/// Parses decimal, `0x`, `0b` and `0o` integers with `_` separators. The value
+/// has to fit in 32 bits either as signed or as unsigned number; unsigned ones
+/// are stored by their bit pattern, so `0xFFFFFFFF` is `-1`.
+fn parse_int(text: &str) -> Result<i32, LiteralKind> {
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(v) => (true, v),
+        None => (false, text),
+    };
+    let (radix, digits) = match unsigned.get(..2) {
+        Some("0x" | "0X") => (16, &unsigned[2..]),
+        Some("0b" | "0B") => (2, &unsigned[2..]),
+        Some("0o" | "0O") => (8, &unsigned[2..]),
+        _ => (10, unsigned),
+    };
+    if digits.is_empty()
+        || digits.starts_with('_')
+        || !digits.chars().all(|v| v == '_' || v.is_digit(radix))
+    {
+        return Err(LiteralKind::Number);
+    }
+
+    let value =
+        u64::from_str_radix(&digits.replace('_', ""), radix).map_err(|_| LiteralKind::Overflow)?;
+    match (negative, value) {
+        (true, v) if v <= 1 << 31 => Ok((v as i64).wrapping_neg() as i32),
+        (false, v) if v <= u32::MAX as u64 => Ok(v as u32 as i32),
+        _ => Err(LiteralKind::Overflow),
+    }
 }
 
+fn parse_num(tokens: &mut Tokens) -> Result<Expression, CompileError> {
+    let (num, span) = expect_atom(tokens, ExpressionType::Expression)?;
+    match parse_int(&num) {
+        Ok(num) => Ok(Expression::Value(num)),
+        Err(kind) => {
+            let error = malformed(tokens, kind, span);
+            tokens.diagnostics.report(error);
+            Ok(Expression::Value(0))
+        }
+    }
+}
+
+fn parse_str(tokens: &mut Tokens) -> Result<Expression, CompileError> {
+    let span = tokens.span();
+    match tokens.next().map(|v| v.kind) {
+        Some(TokenKind::Str(literal)) => Ok(Expression::Str(literal)),
+        _ => Err(malformed(tokens, LiteralKind::Str, span)),
+    }
+}
+
+/// Array literals hold only numbers and characters, so that they can be laid
+/// out in static memory.
+fn parse_array(tokens: &mut Tokens) -> Result<Expression, CompileError> {
+    tokens.next();
+
+    let mut items = Vec::new();
+    loop {
+        let span = tokens.span();
+        let item = match tokens.peek().map(|v| v.kind.clone()) {
+            Some(TokenKind::CloseBracket) => break,
+            Some(TokenKind::Char(_) | TokenKind::Invalid) => parse_char(tokens)?,
+            Some(TokenKind::Atom(atom)) if is_number(&atom) => parse_num(tokens)?,
+            _ => return Err(ExpressionType::ArrayItem.to_error(tokens.source, span)),
+        };
+        if let Expression::Value(num) = item {
+            items.push(num);
+        }
+    }
+    tokens.next();
+
+    Ok(Expression::Array(items))
+}
+
+/// Parses a quoted datum. Names inside it are symbols, not variables, and a
+/// nested `'(...)` stands for `(quote (...))`.
+fn parse_datum(tokens: &mut Tokens) -> Result<Datum, CompileError> {
+    let span = tokens.span();
+    match tokens.peek().map(|v| v.kind.clone()) {
+        Some(TokenKind::Open) => {
+            tokens.next();
+            let mut items = Vec::new();
+            while matches!(tokens.peek(), Some(v) if v.kind != TokenKind::Close) {
+                items.push(parse_datum(tokens)?);
+            }
+            expect_close(tokens)?;
+
+            Ok(Datum::List(items))
+        }
+        Some(TokenKind::Quote) => {
+            tokens.next();
+            let quoted = parse_datum(tokens)?;
+
+            Ok(Datum::List(vec![
+                Datum::Symbol("quote".to_string()),
+                quoted,
+            ]))
+        }
+        Some(TokenKind::Char(_) | TokenKind::Invalid) => match parse_char(tokens)? {
+            Expression::Value(num) => Ok(Datum::Int(num)),
+            _ => unreachable!(),
+        },
+        Some(TokenKind::Atom(atom)) if is_number(&atom) => match parse_num(tokens)? {
+            Expression::Value(num) => Ok(Datum::Int(num)),
+            _ => unreachable!(),
+        },
+        Some(TokenKind::Atom(atom)) => {
+            tokens.next();
+
+            Ok(Datum::Symbol(atom))
+        }
+        _ => Err(ExpressionType::Datum.to_error(tokens.source, span)),
+    }
+}
+
+/// Parses both `'datum` and `(quote datum)`.
+fn parse_quote(tokens: &mut Tokens) -> Result<Expression, CompileError> {
+    tokens.next();
+
+    Ok(Expression::Quote(parse_datum(tokens)?))
+}
+
+/// Value of an optional parameter: a number, a character or a string.
+fn default_value(kind: &TokenKind) -> Option<Expression> {
+    match kind {
+        TokenKind::Char(chr) => Some(Expression::Value((*chr).into())),
+        TokenKind::Str(literal) => Some(Expression::Str(literal.clone())),
+        TokenKind::Atom(atom) if is_number(atom) => parse_int(atom).ok().map(Expression::Value),
+        _ => None,
+    }
+}
+
+/// Parses the parameters: names, then `(name default)` pairs of the optional
+/// ones, then possibly `&rest name`, whose annotation is the type of each
+/// extra argument.
 fn parse_arg_names(
-    expr_pointer: &mut &str,
+    tokens: &mut Tokens,
     declared: &mut Declared,
-) -> Result<Vec<String>, String> {
-    *expr_pointer = expr_pointer.trim_start();
-    if expr_pointer.as_bytes()[0] != b'(' {
-        return Err(ExpressionType::Args.to_explained_string(expr_pointer));
+) -> Result<(Vec<String>, Signature), CompileError> {
+    let span = tokens.span();
+    if tokens.next().map(|v| v.kind) != Some(TokenKind::Open) {
+        return Err(ExpressionType::Args.to_error(tokens.source, span));
     }
 
-    let (args, other) = split_first(&expr_pointer[1..], &[')'], ExpressionType::Args)?;
-    *expr_pointer = &other[1..];
-
     let mut args_splitted = Vec::new();
-    for arg in args.split_ascii_whitespace() {
-        declared.var_dec(arg)?;
-        args_splitted.push(arg.to_string());
+    let mut signature = Signature::fixed(0);
+    loop {
+        let span = tokens.span();
+        let optional = match tokens.peek().map(|v| v.kind.clone()) {
+            Some(TokenKind::Close) => break,
+            Some(TokenKind::Atom(atom)) if atom == "&rest" && signature.rest == Rest::None => {
+                tokens.next();
+                signature.rest = Rest::Array;
+                continue;
+            }
+            Some(TokenKind::Open) => {
+                tokens.next();
+                true
+            }
+            _ => false,
+        };
+        // a required parameter can't follow an optional one, and `&rest` is
+        // followed by exactly one name
+        let misplaced = match signature.rest {
+            Rest::None => !optional && !signature.defaults.is_empty(),
+            _ => optional || args_splitted.len() > signature.params,
+        };
+        if misplaced {
+            return Err(ExpressionType::Args.to_error(tokens.source, span));
+        }
+
+        let (arg, ty, span) = expect_annotated(tokens, ExpressionType::Args)?;
+        if optional {
+            let span = tokens.span();
+            let value = tokens
+                .next()
+                .and_then(|v| default_value(&v.kind))
+                .ok_or_else(|| ExpressionType::Default.to_error(tokens.source, span))?;
+            signature.defaults.push(value);
+            expect_close(tokens)?;
+        }
+        match signature.rest {
+            Rest::None => {
+                signature.params += 1;
+                signature.types.push(ty.unwrap_or(Type::Any));
+            }
+            _ => signature.rest_type = ty.unwrap_or(Type::Any),
+        }
+        if args_splitted.contains(&arg) {
+            let error = CompileError::Redeclaration {
+                name: arg.clone(),
+                at: Some(tokens.locate(span)),
+            };
+            tokens.diagnostics.report(error);
+        } else {
+            declare_var(tokens, declared, &arg, span);
+        }
+        args_splitted.push(arg);
+    }
+    let span = tokens.span();
+    tokens.next();
+    if signature.rest != Rest::None && args_splitted.len() == signature.params {
+        return Err(ExpressionType::Args.to_error(tokens.source, span));
     }
 
-    Ok(args_splitted)
+    Ok((args_splitted, signature))
 }
 
-fn parse_fn_def(expr_pointer: &mut &str, declared: &mut Declared) -> Result<Expression, String> {
-    let (fn_name, other) = split_first(
-        expr_pointer.trim_start()[2..].trim_start(),
-        &[' '],
-        ExpressionType::FnDef,
-    )?;
-    *expr_pointer = other;
+fn parse_fn_def(
+    tokens: &mut Tokens,
+    declared: &mut Declared,
+    top_level: bool,
+) -> Result<Expression, CompileError> {
+    tokens.next();
+    let (fn_name, span) = expect_atom(tokens, ExpressionType::FnDef)?;
+    let fn_name = declared.qualify(&fn_name);
+    let forward = top_level && declared.forward.remove(&fn_name);
 
     let mut novar = declared.novar();
-    let arg_names = parse_arg_names(expr_pointer, &mut novar)?;
-    novar.fn_def(fn_name, arg_names.len())?;
+    let (arg_names, signature) = parse_arg_names(tokens, &mut novar)?;
+    if forward {
+        novar.fns.insert(fn_name.clone(), signature);
+    } else {
+        declare(tokens, novar.fn_def(&fn_name, signature), span);
+    }
 
-    let expr = Box::new(parse_expr(expr_pointer, &mut novar)?);
+    let expr = parse_expr(tokens, &mut novar);
     declared.fns = novar.fns;
+    let expr = Box::new(expr?);
 
     Ok(Expression::FnDef {
-        name: fn_name.to_string(),
+        name: fn_name,
         arguments: arg_names,
+        captures: Vec::new(),
         expr,
     })
 }
 
-fn parse_var_def(expr_pointer: &mut &str, declared: &mut Declared) -> Result<Expression, String> {
-    let (var_name, other) = split_first(
-        expr_pointer.trim_start()[3..].trim_start(),
-        &[' '],
-        ExpressionType::VarDef,
-    )?;
-    *expr_pointer = other;
+fn parse_def(
+    tokens: &mut Tokens,
+    declared: &mut Declared,
+    top_level: bool,
+) -> Result<Expression, CompileError> {
+    let def_span = tokens.span();
+    tokens.next();
+    if !top_level {
+        let error = CompileError::Misplaced {
+            form: "def".to_string(),
+            at: Some(tokens.locate(def_span)),
+        };
+        tokens.diagnostics.report(error);
+    }
+    let (name, span) = expect_atom(tokens, ExpressionType::Def)?;
+    let name = declared.qualify(&name);
+
+    let init = Box::new(parse_expr(tokens, declared)?);
+    declare(tokens, declared.global_def(&name), span);
+
+    Ok(Expression::Def { name, init })
+}
+
+/// Parses `(struct name fields...)` into the constructor `name` taking the
+/// fields in order, and declares `name_field` and `set_name_field` for every
+/// field. The latter two have no bodies: their calls compile to a direct
+/// access, and `ref` does not take them for the typed object.
+fn parse_struct(
+    tokens: &mut Tokens,
+    declared: &mut Declared,
+    top_level: bool,
+) -> Result<Expression, CompileError> {
+    let struct_span = tokens.span();
+    tokens.next();
+    if !top_level {
+        let error = CompileError::Misplaced {
+            form: "struct".to_string(),
+            at: Some(tokens.locate(struct_span)),
+        };
+        tokens.diagnostics.report(error);
+    }
+    let (name, span) = expect_atom(tokens, ExpressionType::Struct)?;
+    let at = tokens.locate(span);
 
-    let init = Box::new(parse_expr(expr_pointer, declared)?);
-    declared.var_dec(var_name)?;
+    let mut fields: Vec<String> = Vec::new();
+    loop {
+        let field_span = tokens.span();
+        let field = match tokens.peek().map(|v| &v.kind) {
+            Some(TokenKind::Close) if !fields.is_empty() => break,
+            _ => expect_atom(tokens, ExpressionType::Struct)?.0,
+        };
+        if fields.contains(&field) {
+            let error = CompileError::Redeclaration {
+                name: field,
+                at: Some(tokens.locate(field_span)),
+            };
+            tokens.diagnostics.report(error);
+        } else {
+            fields.push(field);
+        }
+    }
 
-    let expr = Box::new(parse_expr(expr_pointer, declared)?);
-    declared.var_undec(var_name)?;
+    let constructor = Signature {
+        result: Type::Ptr,
+        ..Signature::fixed(fields.len())
+    };
+    declare(
+        tokens,
+        declared.fn_def(&declared.qualify(&name), constructor),
+        span,
+    );
+    // the pointer to the new struct, named so that it never clashes with a
+    // field
+    let this = "new struct".to_string();
+    let mut init: Vec<_> = fields
+        .iter()
+        .enumerate()
+        .map(|(idx, field)| Expression::Field {
+            name: declared.qualify(&name),
+            object: Box::new(Expression::Var(this.clone())),
+            offset: idx as u16 * 4,
+            value: Some(Box::new(Expression::Var(field.clone()))),
+            at: at.clone(),
+        })
+        .collect();
+    init.push(Expression::Var(this.clone()));
+    let fn_def = Expression::FnDef {
+        name: declared.qualify(&name),
+        arguments: fields.clone(),
+        captures: Vec::new(),
+        expr: Box::new(Expression::VarDef {
+            name: this,
+            ty: None,
+            init: Box::new(Expression::Fn {
+                name: "alloc".to_string(),
+                args: vec![Expression::Value(fields.len() as i32 * 4)],
+                at: at.clone(),
+            }),
+            expr: Box::new(Expression::Do(init)),
+            at: at.clone(),
+        }),
+    };
+
+    for (idx, field) in fields.iter().enumerate() {
+        let offset = idx as u16 * 4;
+        let accessors = [
+            (format!("{}_{}", name, field), Accessor::Get(offset)),
+            (format!("set_{}_{}", name, field), Accessor::Set(offset)),
+        ];
+        for (accessor_name, accessor) in accessors {
+            let accessor_name = declared.qualify(&accessor_name);
+            let mut signature = Signature::fixed(match accessor {
+                Accessor::Get(_) => 1,
+                Accessor::Set(_) => 2,
+            });
+            signature.types[0] = Type::Ptr;
+            // a clashing name keeps referring to what it was declared for
+            let result = declared.fn_def(&accessor_name, signature);
+            if result.is_ok() {
+                declared.accessors.insert(accessor_name, accessor);
+            }
+            declare(tokens, result, span);
+        }
+    }
+
+    Ok(fn_def)
+}
+
+/// Access to a struct field through the accessor with the arguments.
+fn field_access(
+    name: String,
+    accessor: Accessor,
+    args: Vec<Expression>,
+    at: Location,
+) -> Expression {
+    let mut args = args.into_iter();
+    let object = Box::new(args.next().unwrap_or(Expression::Value(0)));
+    match accessor {
+        Accessor::Get(offset) => Expression::Field {
+            name,
+            object,
+            offset,
+            value: None,
+            at,
+        },
+        Accessor::Set(offset) => Expression::Field {
+            name,
+            object,
+            offset,
+            value: args.next().map(Box::new),
+            at,
+        },
+    }
+}
+
+/// Parses `(params) body` of a macro. The body is a quasiquoted template or
+/// a single parameter; unquotes in the template may only name parameters.
+fn parse_macro_rule(tokens: &mut Tokens) -> Result<MacroRule, CompileError> {
+    let span = tokens.span();
+    if tokens.next().map(|v| v.kind) != Some(TokenKind::Open) {
+        return Err(ExpressionType::Args.to_error(tokens.source, span));
+    }
+    let mut rule = MacroRule {
+        params: Vec::new(),
+        rest: None,
+        template: Vec::new(),
+    };
+    loop {
+        let span = tokens.span();
+        match tokens.next().map(|v| v.kind) {
+            Some(TokenKind::Close) => break,
+            Some(TokenKind::Atom(name)) if rule.rest.is_none() => {
+                let rest = name == "&rest";
+                let (name, span) = match rest {
+                    true => expect_atom(tokens, ExpressionType::Args)?,
+                    false => (name, span),
+                };
+                if rule.is_param(&name) {
+                    let error = CompileError::Redeclaration {
+                        name,
+                        at: Some(tokens.locate(span)),
+                    };
+                    tokens.diagnostics.report(error);
+                } else if rest {
+                    rule.rest = Some(name);
+                } else {
+                    rule.params.push(name);
+                }
+            }
+            _ => return Err(ExpressionType::Args.to_error(tokens.source, span)),
+        }
+    }
+
+    let span = tokens.span();
+    match tokens.peek().map(|v| v.kind.clone()) {
+        Some(TokenKind::Quasiquote) => {
+            tokens.next();
+            rule.template = tokens
+                .take_group()
+                .ok_or_else(|| ExpressionType::Expression.to_error(tokens.source, span))?;
+        }
+        Some(TokenKind::Atom(name)) if rule.is_param(&name) => {
+            let param = tokens.next().unwrap();
+            rule.template = vec![
+                Token {
+                    kind: TokenKind::Unquote,
+                    span,
+                },
+                param,
+            ];
+        }
+        _ => return Err(ExpressionType::MacroBody.to_error(tokens.source, span)),
+    }
+
+    let mut template = rule.template.iter().peekable();
+    while let Some(token) = template.next() {
+        match token.kind {
+            TokenKind::Unquote | TokenKind::UnquoteSplicing => match template.next() {
+                Some(Token {
+                    kind: TokenKind::Atom(name),
+                    ..
+                }) if rule.is_param(name) => (),
+                Some(Token {
+                    kind: TokenKind::Atom(name),
+                    span,
+                }) => {
+                    return Err(CompileError::UnknownName {
+                        name: name.clone(),
+                        at: Some(tokens.locate(*span)),
+                    })
+                }
+                _ => return Err(ExpressionType::MacroBody.to_error(tokens.source, token.span)),
+            },
+            TokenKind::Quasiquote => {
+                return Err(ExpressionType::MacroBody.to_error(tokens.source, token.span))
+            }
+            _ => (),
+        }
+    }
+
+    Ok(rule)
+}
+
+/// Parses `(defmacro name (params) body)` or, for a macro with several
+/// clauses chosen by the number of arguments,
+/// `(defmacro name ((params) body) ...)`.
+fn parse_defmacro(
+    tokens: &mut Tokens,
+    declared: &mut Declared,
+    top_level: bool,
+) -> Result<Expression, CompileError> {
+    let defmacro_span = tokens.span();
+    tokens.next();
+    if !top_level {
+        let error = CompileError::Misplaced {
+            form: "defmacro".to_string(),
+            at: Some(tokens.locate(defmacro_span)),
+        };
+        tokens.diagnostics.report(error);
+    }
+    let (name, span) = expect_atom(tokens, ExpressionType::Defmacro)?;
+    let name = declared.qualify(&name);
+
+    let mut rules = Vec::new();
+    if tokens.peek_at(1).map(|v| &v.kind) == Some(&TokenKind::Open) {
+        while tokens.peek().map(|v| &v.kind) == Some(&TokenKind::Open) {
+            tokens.next();
+            rules.push(parse_macro_rule(tokens)?);
+            expect_close(tokens)?;
+        }
+    } else {
+        rules.push(parse_macro_rule(tokens)?);
+    }
+    declare(tokens, declared.macro_def(&name, rules), span);
+
+    Ok(Expression::Value(0))
+}
+
+/// `(import "path" namespace)` at the top level of a file.
+pub struct Import {
+    pub path: String,
+    pub namespace: Option<String>,
+    pub span: Span,
+}
+
+/// Finds the imports of the file without parsing it, so that the modules can
+/// be parsed before it. Malformed ones are left for the parser to report.
+pub fn imports(source: &Source) -> Vec<Import> {
+    let Ok((tokens, _)) = tokenize(source) else {
+        return Vec::new();
+    };
+    let mut imports = Vec::new();
+    let mut depth = 0;
+    for (idx, token) in tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::Open if depth == 0 => {
+                let kinds: Vec<&TokenKind> =
+                    tokens[idx + 1..].iter().take(3).map(|v| &v.kind).collect();
+                if let [TokenKind::Atom(import), TokenKind::Str(path), ..] = kinds[..] {
+                    if import == "import" {
+                        let namespace = match kinds.get(2) {
+                            Some(TokenKind::Atom(namespace)) => Some(namespace.clone()),
+                            _ => None,
+                        };
+                        imports.push(Import {
+                            path: String::from_utf8_lossy(path).to_string(),
+                            namespace,
+                            span: tokens[idx + 2].span,
+                        });
+                    }
+                }
+                depth += 1;
+            }
+            TokenKind::Open => depth += 1,
+            TokenKind::Close if depth > 0 => depth -= 1,
+            _ => (),
+        }
+    }
+
+    imports
+}
+
+/// Checks the form of an import; the module itself is already parsed.
+fn parse_import(tokens: &mut Tokens, top_level: bool) -> Result<Expression, CompileError> {
+    let import_span = tokens.span();
+    tokens.next();
+    if !top_level {
+        let error = CompileError::Misplaced {
+            form: "import".to_string(),
+            at: Some(tokens.locate(import_span)),
+        };
+        tokens.diagnostics.report(error);
+    }
+    let span = tokens.span();
+    if !matches!(tokens.next().map(|v| v.kind), Some(TokenKind::Str(_))) {
+        return Err(ExpressionType::Import.to_error(tokens.source, span));
+    }
+    if let Some(TokenKind::Atom(_)) = tokens.peek().map(|v| &v.kind) {
+        tokens.next();
+    }
+
+    Ok(Expression::Value(0))
+}
+
+/// Replaces the macro call at the current position with its expansion.
+fn expand_macro(tokens: &mut Tokens, name: &str, rules: &[MacroRule]) -> Result<(), CompileError> {
+    let open = tokens.position();
+    let span = tokens.span();
+    tokens.next();
+    tokens.next();
+
+    let mut args = Vec::new();
+    while matches!(tokens.peek(), Some(v) if v.kind != TokenKind::Close) {
+        let span = tokens.span();
+        let arg = tokens
+            .take_group()
+            .ok_or_else(|| ExpressionType::Close.to_error(tokens.source, span))?;
+        args.push(arg);
+    }
+    expect_close(tokens)?;
+
+    let Some(rule) = rules.iter().find(|v| v.accepts(args.len())) else {
+        let (expected, bound) = MacroRule::arity(rules, args.len());
+        return Err(CompileError::Arity {
+            name: name.to_string(),
+            expected,
+            bound,
+            found: args.len(),
+            at: Some(tokens.locate(span)),
+        });
+    };
+    if tokens.expansions >= MAX_EXPANSIONS {
+        return Err(CompileError::MacroExpansion {
+            name: name.to_string(),
+            at: Some(tokens.locate(span)),
+        });
+    }
+    tokens.expansions += 1;
+    let expansion = rule.expand(&args, span);
+    tokens.splice(open, expansion);
+
+    Ok(())
+}
+
+fn parse_lambda(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    tokens.next();
+
+    let mut inner = declared.lambda();
+    let span = tokens.span();
+    let (arguments, signature) = parse_arg_names(tokens, &mut inner)?;
+    // `apply` passes the arguments as they are, and the checker does not see
+    // the calls
+    if signature.rest != Rest::None
+        || !signature.defaults.is_empty()
+        || signature.types.iter().any(|v| *v != Type::Any)
+    {
+        return Err(ExpressionType::Args.to_error(tokens.source, span));
+    }
+    let expr = parse_expr(tokens, &mut inner);
+    declared.fns = inner.fns;
+    let expr = Box::new(expr?);
+
+    let mut captures: Vec<String> = Vec::new();
+    for var in &declared.vars {
+        if inner.used.contains(var) && !arguments.contains(var) && !captures.contains(var) {
+            captures.push(var.clone());
+        }
+    }
+    declared.used.extend(captures.iter().cloned());
+
+    Ok(Expression::Lambda {
+        arguments,
+        captures,
+        expr,
+    })
+}
+
+fn parse_ref(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    tokens.next();
+    let (name, span) = expect_atom(tokens, ExpressionType::Ref)?;
+    let name = declared.resolve(&name);
+    let signature = match declared.fns.get(&name) {
+        Some(signature) if !declared.is_var(&name) => signature,
+        _ => {
+            return Err(CompileError::UnknownName {
+                name,
+                at: Some(tokens.locate(span)),
+            })
+        }
+    };
+    // `apply` passes the arguments as they are, the same as to a lambda
+    if signature.rest != Rest::None
+        || !signature.defaults.is_empty()
+        || signature.types.iter().any(|v| *v != Type::Any)
+    {
+        return Err(ExpressionType::Ref.to_error(tokens.source, span));
+    }
+
+    Ok(Expression::Closure {
+        name,
+        captures: Vec::new(),
+    })
+}
+
+fn parse_apply(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    tokens.next();
+
+    let f = Box::new(parse_expr(tokens, declared)?);
+    let mut args = Vec::new();
+    while matches!(tokens.peek(), Some(v) if v.kind != TokenKind::Close) {
+        args.push(parse_expr(tokens, declared)?);
+    }
+
+    Ok(Expression::Apply { f, args })
+}
+
+fn parse_var_def(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    tokens.next();
+    let (var_name, ty, span) = expect_annotated(tokens, ExpressionType::VarDef)?;
+
+    let init = Box::new(parse_expr(tokens, declared)?);
+    declare_var(tokens, declared, &var_name, span);
+
+    let expr = Box::new(parse_expr(tokens, declared)?);
+    declared
+        .var_undec(&var_name)
+        .map_err(|e| e.at(tokens.locate(span)))?;
 
     Ok(Expression::VarDef {
-        name: var_name.to_string(),
+        name: var_name,
+        ty,
         init,
         expr,
+        at: tokens.locate(span),
     })
 }
 
-fn parse_case(expr_pointer: &mut &str, declared: &mut Declared) -> Result<Expression, String> {
-    *expr_pointer = &expr_pointer.trim_start()[4..];
+fn parse_case(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    tokens.next();
 
-    let condition = Box::new(parse_expr(expr_pointer, declared)?);
-    let t = Box::new(parse_expr(expr_pointer, declared)?);
-    let f = Box::new(parse_expr(expr_pointer, declared)?);
+    let condition = Box::new(parse_expr(tokens, declared)?);
+    let t = Box::new(parse_expr(tokens, declared)?);
+    let f = Box::new(parse_expr(tokens, declared)?);
 
     Ok(Expression::Case { condition, t, f })
 }
 
-fn parse_for(expr_pointer: &mut &str, declared: &mut Declared) -> Result<Expression, String> {
-    let (var, other) = split_first(
-        expr_pointer.trim_start()[3..].trim_start(),
-        &[' '],
-        ExpressionType::For,
-    )?;
-    *expr_pointer = other;
+fn parse_for(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    tokens.next();
+    let (var, span) = expect_atom(tokens, ExpressionType::For)?;
 
-    declared.var_dec(var)?;
-    let next_val = Box::new(parse_expr(expr_pointer, declared)?);
-    let while_expr = Box::new(parse_expr(expr_pointer, declared)?);
-    let expr = Box::new(parse_expr(expr_pointer, declared)?);
-    declared.var_undec(var)?;
+    declare_var(tokens, declared, &var, span);
+    declared.loops += 1;
+    let next_val = Box::new(parse_expr(tokens, declared)?);
+    let while_expr = Box::new(parse_expr(tokens, declared)?);
+    let expr = Box::new(parse_expr(tokens, declared)?);
+    declared.loops -= 1;
+    declared
+        .var_undec(&var)
+        .map_err(|e| e.at(tokens.locate(span)))?;
 
     Ok(Expression::For {
-        var: var.to_string(),
+        var,
         next_val,
         while_expr,
         expr,
     })
 }
 
-fn parse_fn_or_var(expr_pointer: &mut &str, declared: &mut Declared) -> Result<Expression, String> {
-    let (name, other) = split_first(
-        expr_pointer.trim_start(),
-        &[' ', ')'],
-        ExpressionType::FnOrVar,
-    )?;
-    *expr_pointer = other;
+fn parse_set(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    tokens.next();
+    let (name, span) = expect_atom(tokens, ExpressionType::Set)?;
+    let name = match declared.is_var(&name) {
+        true => name,
+        false => declared.resolve(&name),
+    };
+    if !declared.is_var(&name) && !declared.globals.contains(&name) {
+        return Err(CompileError::UnknownName {
+            name,
+            at: Some(tokens.locate(span)),
+        });
+    }
+    declared.used.insert(name.clone());
 
-    if let Some(args_count) = declared.fns.get(name) {
-        let mut args: Vec<Expression> = Vec::new();
-        for _ in 0..*args_count {
-            args.push(parse_expr(expr_pointer, declared)?);
+    let expr = Box::new(parse_expr(tokens, declared)?);
+
+    Ok(Expression::Set {
+        name,
+        expr,
+        at: tokens.locate(span),
+    })
+}
+
+fn parse_do(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    tokens.next();
+
+    let mut exprs = vec![parse_expr(tokens, declared)?];
+    while matches!(tokens.peek(), Some(v) if v.kind != TokenKind::Close) {
+        exprs.push(parse_expr(tokens, declared)?);
+    }
+
+    Ok(Expression::Do(exprs))
+}
+
+/// Parses `and` and `or`, which take any positive number of expressions.
+fn parse_logic(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    let (form, _) = expect_atom(tokens, ExpressionType::Expression)?;
+
+    let mut exprs = vec![parse_expr(tokens, declared)?];
+    while matches!(tokens.peek(), Some(v) if v.kind != TokenKind::Close) {
+        exprs.push(parse_expr(tokens, declared)?);
+    }
+
+    Ok(match form.as_str() {
+        "and" => Expression::And(exprs),
+        _ => Expression::Or(exprs),
+    })
+}
+
+/// Parses `(cond (condition expr)... [(else expr)])` into nested `case`s. The
+/// value is 0 if no condition holds and there is no `else`.
+fn parse_cond(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    tokens.next();
+
+    let mut clauses = Vec::new();
+    let mut default = Expression::Value(0);
+    while matches!(tokens.peek(), Some(v) if v.kind != TokenKind::Close) {
+        let span = tokens.span();
+        if tokens.next().map(|v| v.kind) != Some(TokenKind::Open) {
+            return Err(ExpressionType::Clause.to_error(tokens.source, span));
         }
+        if let Some(TokenKind::Atom(atom)) = tokens.peek().map(|v| &v.kind) {
+            if atom == "else" {
+                // the clause has to be the last one, which the closing
+                // parenthesis of `cond` checks
+                tokens.next();
+                default = parse_expr(tokens, declared)?;
+                expect_close(tokens)?;
+                break;
+            }
+        }
+        let condition = parse_expr(tokens, declared)?;
+        let expr = parse_expr(tokens, declared)?;
+        expect_close(tokens)?;
+        clauses.push((condition, expr));
+    }
 
-        Ok(Expression::Fn {
-            name: name.to_string(),
-            args,
-        })
-    } else if declared.vars.contains(name) {
-        Ok(Expression::Var(name.to_string()))
+    Ok(clauses
+        .into_iter()
+        .rev()
+        .fold(default, |f, (condition, t)| Expression::Case {
+            condition: Box::new(condition),
+            t: Box::new(t),
+            f: Box::new(f),
+        }))
+}
+
+/// Parses a key of a `switch` clause, which must differ from all the keys
+/// before it.
+fn parse_switch_key(tokens: &mut Tokens, used: &mut HashSet<i32>) -> Result<i32, CompileError> {
+    let span = tokens.span();
+    let key = match tokens.peek().map(|v| v.kind.clone()) {
+        Some(TokenKind::Char(_) | TokenKind::Invalid) => parse_char(tokens)?,
+        Some(TokenKind::Atom(atom)) if is_number(&atom) => parse_num(tokens)?,
+        _ => return Err(ExpressionType::SwitchKey.to_error(tokens.source, span)),
+    };
+    match key {
+        Expression::Value(key) if used.insert(key) => Ok(key),
+        _ => Err(ExpressionType::SwitchKey.to_error(tokens.source, span)),
+    }
+}
+
+/// Parses `(switch expr (key expr)... [(else expr)])`, where the key of a
+/// clause is a number, a character or a parenthesized list of them. The value
+/// is 0 if no key matches and there is no `else`.
+fn parse_switch(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    tokens.next();
+
+    let expr = Box::new(parse_expr(tokens, declared)?);
+    let mut clauses = Vec::new();
+    let mut default = Expression::Value(0);
+    let mut used = HashSet::new();
+    while matches!(tokens.peek(), Some(v) if v.kind != TokenKind::Close) {
+        let span = tokens.span();
+        if tokens.next().map(|v| v.kind) != Some(TokenKind::Open) {
+            return Err(ExpressionType::Clause.to_error(tokens.source, span));
+        }
+        let keys = match tokens.peek().map(|v| v.kind.clone()) {
+            Some(TokenKind::Atom(atom)) if atom == "else" => {
+                tokens.next();
+                default = parse_expr(tokens, declared)?;
+                expect_close(tokens)?;
+                break;
+            }
+            Some(TokenKind::Open) => {
+                tokens.next();
+                let mut keys = Vec::new();
+                while matches!(tokens.peek(), Some(v) if v.kind != TokenKind::Close) {
+                    keys.push(parse_switch_key(tokens, &mut used)?);
+                }
+                tokens.next();
+                keys
+            }
+            _ => vec![parse_switch_key(tokens, &mut used)?],
+        };
+        let expr = parse_expr(tokens, declared)?;
+        expect_close(tokens)?;
+        clauses.push((keys, expr));
+    }
+
+    Ok(Expression::Switch {
+        expr,
+        clauses,
+        default: Box::new(default),
+    })
+}
+
+fn parse_while(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    tokens.next();
+
+    declared.loops += 1;
+    let condition = Box::new(parse_expr(tokens, declared)?);
+    let expr = Box::new(parse_expr(tokens, declared)?);
+    declared.loops -= 1;
+
+    Ok(Expression::While { condition, expr })
+}
+
+/// Parses `(try body (catch name handler))`; the name is visible in the
+/// handler only.
+fn parse_try(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    tokens.next();
+
+    let body = Box::new(parse_expr(tokens, declared)?);
+    let span = tokens.span();
+    if tokens.next().map(|v| v.kind) != Some(TokenKind::Open) {
+        return Err(ExpressionType::Catch.to_error(tokens.source, span));
+    }
+    let (keyword, span) = expect_atom(tokens, ExpressionType::Catch)?;
+    if keyword != "catch" {
+        return Err(ExpressionType::Catch.to_error(tokens.source, span));
+    }
+    let (var, span) = expect_atom(tokens, ExpressionType::Catch)?;
+
+    declare_var(tokens, declared, &var, span);
+    let handler = Box::new(parse_expr(tokens, declared)?);
+    declared
+        .var_undec(&var)
+        .map_err(|e| e.at(tokens.locate(span)))?;
+    expect_close(tokens)?;
+
+    Ok(Expression::Try { body, var, handler })
+}
+
+fn parse_throw(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    let (_, span) = expect_atom(tokens, ExpressionType::Expression)?;
+
+    Ok(Expression::Throw {
+        code: Box::new(parse_expr(tokens, declared)?),
+        at: tokens.locate(span),
+    })
+}
+
+/// Parses `break`, `continue` and `return`. Using them outside of a loop or a
+/// function is reported, but does not stop parsing.
+fn parse_jump(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    let (form, span) = expect_atom(tokens, ExpressionType::Expression)?;
+    let allowed = match form.as_str() {
+        "return" => declared.in_fn,
+        _ => declared.loops > 0,
+    };
+    if !allowed {
+        let error = CompileError::Misplaced {
+            form: form.clone(),
+            at: Some(tokens.locate(span)),
+        };
+        tokens.diagnostics.report(error);
+    }
+
+    Ok(match form.as_str() {
+        "break" => Expression::Break,
+        "continue" => Expression::Continue,
+        _ => Expression::Return(Box::new(parse_expr(tokens, declared)?)),
+    })
+}
+
+fn parse_fn_or_var(
+    tokens: &mut Tokens,
+    declared: &mut Declared,
+) -> Result<Expression, CompileError> {
+    let (name, span) = expect_atom(tokens, ExpressionType::FnOrVar)?;
+    let name = match declared.is_var(&name) {
+        true => name,
+        false => declared.resolve(&name),
+    };
+
+    if declared.is_var(&name) || declared.globals.contains(&name) {
+        declared.used.insert(name.clone());
+        Ok(Expression::Var(name))
+    } else if let Some(signature) = declared.fns.get(&name).cloned() {
+        parse_call(tokens, declared, name, signature, span)
     } else {
-        Err(format!(
-            "Variable '{}' did not declared: {}...",
-            &name,
-            error_code(expr_pointer)
-        ))
+        Err(CompileError::UnknownName {
+            name,
+            at: Some(tokens.locate(span)),
+        })
     }
 }
 
-fn parse_expr(expr_pointer: &mut &str, declared: &mut Declared) -> Result<Expression, String> {
-    *expr_pointer = expr_pointer.trim_start();
-    match expr_pointer
-        .chars()
-        .next()
-        .ok_or_else(|| format!("Parsing error: {}...", error_code(expr_pointer)))?
-    {
-        '(' => {
-            *expr_pointer = &expr_pointer[1..];
-            let expr = parse_expr(expr_pointer, declared);
-            *expr_pointer = &expr_pointer.trim_start()[1..];
-            return expr;
+/// Parses the arguments of a call, putting the default values in place of the
+/// omitted optional ones.
+fn parse_call(
+    tokens: &mut Tokens,
+    declared: &mut Declared,
+    name: String,
+    signature: Signature,
+    span: Span,
+) -> Result<Expression, CompileError> {
+    let more = |tokens: &Tokens| {
+        !matches!(
+            tokens.peek().map(|v| &v.kind),
+            None | Some(TokenKind::Close)
+        )
+    };
+    let mut args: Vec<Expression> = Vec::new();
+    while args.len() < signature.required() || (args.len() < signature.params && more(tokens)) {
+        if !more(tokens) {
+            let bound =
+                match signature.required() < signature.params || signature.rest != Rest::None {
+                    true => Bound::AtLeast,
+                    false => Bound::Exactly,
+                };
+            return Err(CompileError::Arity {
+                name,
+                expected: signature.required(),
+                bound,
+                found: args.len(),
+                at: Some(tokens.locate(span)),
+            });
         }
-        '\'' => {
-            return parse_char(expr_pointer);
+        args.push(parse_expr(tokens, declared)?);
+    }
+    args.extend_from_slice(&signature.defaults[args.len() - signature.required()..]);
+
+    let mut extra = Vec::new();
+    while signature.rest != Rest::None && more(tokens) {
+        extra.push(parse_expr(tokens, declared)?);
+    }
+    let at = tokens.locate(span);
+    match signature.rest {
+        Rest::None => Ok(match declared.accessors.get(&name) {
+            Some(accessor) => field_access(name, *accessor, args, at),
+            None => Expression::Fn { name, args, at },
+        }),
+        Rest::Array => {
+            args.push(Expression::Rest(extra));
+            Ok(Expression::Fn { name, args, at })
         }
-        '\"' => {
-            return parse_str(expr_pointer);
+        Rest::Fold => Ok(extra.into_iter().fold(
+            Expression::Fn {
+                name: name.clone(),
+                args,
+                at: at.clone(),
+            },
+            |acc, arg| Expression::Fn {
+                name: name.clone(),
+                args: vec![acc, arg],
+                at: at.clone(),
+            },
+        )),
+    }
+}
+
+fn parse_parenthesized(
+    tokens: &mut Tokens,
+    declared: &mut Declared,
+) -> Result<Expression, CompileError> {
+    tokens.next();
+    let inner_span = tokens.span();
+    let expr = parse_expr(tokens, declared)?;
+    let call = match &expr {
+        Expression::Fn { name, args, .. } => Some((name, args.len())),
+        Expression::Field { name, value, .. } => Some((name, 1 + value.is_some() as usize)),
+        _ => None,
+    };
+    if let Some((name, expected)) = call {
+        let mut found = expected;
+        while matches!(tokens.peek(), Some(v) if v.kind != TokenKind::Close) {
+            parse_expr(tokens, declared)?;
+            found += 1;
+        }
+        if found != expected {
+            let bound = match declared.fns.get(name) {
+                Some(signature) if !signature.defaults.is_empty() => Bound::AtMost,
+                _ => Bound::Exactly,
+            };
+            let error = CompileError::Arity {
+                name: name.clone(),
+                expected,
+                bound,
+                found,
+                at: Some(tokens.locate(inner_span)),
+            };
+            tokens.diagnostics.report(error);
         }
-        _ => (),
     }
+    expect_close(tokens)?;
 
-    match expr_pointer
-        .split([' ', ')'])
-        .next()
-        .ok_or_else(|| format!("Parsing error: {}...", error_code(expr_pointer)))?
-    {
-        "fn" => parse_fn_def(expr_pointer, declared),
-        "case" => parse_case(expr_pointer, declared),
-        "for" => parse_for(expr_pointer, declared),
-        "let" => parse_var_def(expr_pointer, declared),
-        other => {
-            if other.parse::<i32>().is_ok() {
-                parse_num(expr_pointer)
-            } else {
-                parse_fn_or_var(expr_pointer, declared)
+    Ok(expr)
+}
+
+fn parse_expr(tokens: &mut Tokens, declared: &mut Declared) -> Result<Expression, CompileError> {
+    // only the outermost parentheses keep the expression top-level
+    let top_level = std::mem::take(&mut declared.top_level);
+    let span = tokens.span();
+    let kind = tokens
+        .peek()
+        .map(|v| v.kind.clone())
+        .ok_or_else(|| ExpressionType::Expression.to_error(tokens.source, span))?;
+
+    match kind {
+        TokenKind::Open => {
+            let open = tokens.position();
+            // expanded in a loop rather than recursively, since a macro may
+            // expand into a call of another one many times in a row
+            while let Some(TokenKind::Atom(name)) = tokens.peek_at(1).map(|v| v.kind.clone()) {
+                let Some(rules) = declared.macro_rules(&name).cloned() else {
+                    break;
+                };
+                match expand_macro(tokens, &name, &rules) {
+                    Ok(()) => (),
+                    Err(e) if tokens.skip_to_matching(open) => {
+                        tokens.diagnostics.report(e);
+                        return Ok(Expression::Value(0));
+                    }
+                    Err(e) => return Err(e),
+                }
+                if tokens.peek().map(|v| &v.kind) != Some(&TokenKind::Open) {
+                    declared.top_level = top_level;
+                    return parse_expr(tokens, declared);
+                }
+            }
+            let vars = declared.vars.clone();
+            let loops = declared.loops;
+            declared.top_level = top_level;
+            match parse_parenthesized(tokens, declared) {
+                Ok(expr) => Ok(expr),
+                Err(e) if tokens.skip_to_matching(open) => {
+                    tokens.diagnostics.report(e);
+                    declared.vars = vars;
+                    declared.loops = loops;
+                    Ok(Expression::Value(0))
+                }
+                Err(e) => Err(e),
             }
         }
+        // quasiquotes are only meaningful in macro templates
+        TokenKind::Close
+        | TokenKind::CloseBracket
+        | TokenKind::Quasiquote
+        | TokenKind::Unquote
+        | TokenKind::UnquoteSplicing => {
+            Err(ExpressionType::Expression.to_error(tokens.source, span))
+        }
+        TokenKind::OpenBracket => parse_array(tokens),
+        TokenKind::Quote => parse_quote(tokens),
+        TokenKind::Char(_) | TokenKind::Invalid => parse_char(tokens),
+        TokenKind::Str(_) => parse_str(tokens),
+        TokenKind::Atom(atom) => match atom.as_str() {
+            "fn" => parse_fn_def(tokens, declared, top_level),
+            "lambda" => parse_lambda(tokens, declared),
+            "ref" => parse_ref(tokens, declared),
+            "quote" => parse_quote(tokens),
+            "apply" => parse_apply(tokens, declared),
+            "def" => parse_def(tokens, declared, top_level),
+            "struct" => parse_struct(tokens, declared, top_level),
+            "defmacro" => parse_defmacro(tokens, declared, top_level),
+            "import" => parse_import(tokens, top_level),
+            "case" => parse_case(tokens, declared),
+            "cond" => parse_cond(tokens, declared),
+            "switch" => parse_switch(tokens, declared),
+            "and" | "or" => parse_logic(tokens, declared),
+            "for" => parse_for(tokens, declared),
+            "while" => parse_while(tokens, declared),
+            "let" => parse_var_def(tokens, declared),
+            "set" => parse_set(tokens, declared),
+            "do" => parse_do(tokens, declared),
+            "break" | "continue" | "return" => parse_jump(tokens, declared),
+            "try" => parse_try(tokens, declared),
+            "throw" => parse_throw(tokens, declared),
+            other => {
+                if is_number(other) {
+                    parse_num(tokens)
+                } else {
+                    parse_fn_or_var(tokens, declared)
+                }
+            }
+        },
     }
 }
 
-pub fn parse(input: String, std_declared: &mut Declared) -> Result<Vec<Expression>, String> {
-    let input = replace_n(input);
-    let input_pointer: &mut &str = &mut &input[..];
+/// Declares the functions defined at the top level of the file before any of
+/// their bodies is parsed, so that they can be called before the definition
+/// and from each other. Malformed definitions and conflicting names are left
+/// for `parse_fn_def` to report.
+fn declare_signatures(tokens: &Tokens, declared: &mut Declared) {
+    let mut depth = 0;
+    let mut idx = 0;
+    while let Some(token) = tokens.peek_at(idx) {
+        match token.kind {
+            TokenKind::Open if depth == 0 => {
+                if let Some((name, signature)) = signature(tokens, idx + 1) {
+                    let name = declared.qualify(&name);
+                    if declared.fn_def(&name, signature).is_ok() {
+                        declared.forward.insert(name);
+                    }
+                }
+                depth += 1;
+            }
+            TokenKind::Open => depth += 1,
+            TokenKind::Close if depth > 0 => depth -= 1,
+            _ => (),
+        }
+        idx += 1;
+    }
+}
+
+/// Name and parameters of the `fn name (args...)` starting at `idx`.
+fn signature(tokens: &Tokens, idx: usize) -> Option<(String, Signature)> {
+    let kind = |n| tokens.peek_at(idx + n).map(|v| &v.kind);
+    let (Some(TokenKind::Atom(keyword)), Some(TokenKind::Atom(name)), Some(TokenKind::Open)) =
+        (kind(0), kind(1), kind(2))
+    else {
+        return None;
+    };
+    if keyword != "fn" {
+        return None;
+    }
+    let mut signature = Signature::fixed(0);
+    let mut n = 3;
+    loop {
+        match (kind(n)?, signature.rest) {
+            (TokenKind::Close, _) => return Some((name.clone(), signature)),
+            (TokenKind::Atom(atom), Rest::None) if atom == "&rest" => signature.rest = Rest::Array,
+            (TokenKind::Atom(_), Rest::None) if signature.defaults.is_empty() => {
+                signature.params += 1;
+                signature.types.push(Type::Any);
+            }
+            (TokenKind::Atom(_), Rest::Array) => (),
+            (TokenKind::Open, Rest::None) => {
+                let (TokenKind::Atom(_), TokenKind::Close) = (kind(n + 1)?, kind(n + 3)?) else {
+                    return None;
+                };
+                signature.defaults.push(default_value(kind(n + 2)?)?);
+                signature.params += 1;
+                signature.types.push(Type::Any);
+                n += 3;
+            }
+            _ => return None,
+        }
+        n += 1;
+    }
+}
+
+/// Parses the whole file. After an error the parser skips to the `)` matching
+/// the innermost enclosing `(` or, if there is none, past the top-level
+/// expression, and goes on, so that all errors up to `diagnostics.max_errors` are reported
+/// at once. Warnings are returned along with the expressions.
+pub fn parse(
+    source: &Source,
+    std_declared: &mut Declared,
+    diagnostics: Diagnostics,
+) -> Result<(Vec<Expression>, Vec<Warning>), Diagnostics> {
+    let mut tokens = Tokens::new(source, diagnostics)?;
     let mut expressions = Vec::new();
-    while !input_pointer.trim().is_empty() && input_pointer.trim_start().as_bytes()[0] == b'(' {
-        expressions.push(parse_expr(input_pointer, std_declared)?);
+    declare_signatures(&tokens, std_declared);
+    while let Some(token) = tokens.peek() {
+        if tokens.diagnostics.is_full() {
+            break;
+        }
+        if token.kind != TokenKind::Open {
+            let error = ExpressionType::Open.to_error(tokens.source, token.span);
+            tokens.diagnostics.report(error);
+            tokens.skip_to_top_level(tokens.position());
+            continue;
+        }
+
+        let start = tokens.position();
+        let vars = std_declared.vars.clone();
+        std_declared.top_level = true;
+        match parse_expr(&mut tokens, std_declared) {
+            Ok(expr) => expressions.push(expr),
+            Err(e) => {
+                tokens.diagnostics.report(e);
+                std_declared.vars = vars;
+                std_declared.loops = 0;
+                tokens.skip_to_top_level(start);
+            }
+        }
+    }
+
+    let diagnostics = tokens.finish();
+    if diagnostics.is_empty() {
+        Ok((expressions, diagnostics.warnings))
+    } else {
+        Err(diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, parse_int};
+    use crate::{
+        error::{Bound, CompileError, Diagnostics, LiteralKind, Warning},
+        util::{
+            declared::{Declared, Rest, Signature},
+            expression::{Datum, Expression, ExpressionType},
+            source::Source,
+        },
+    };
+
+    fn parse_with(text: &str, diagnostics: Diagnostics) -> Result<Vec<Warning>, Diagnostics> {
+        let mut declared = Declared::new();
+        declared.fn_def("+", Signature::fixed(2)).unwrap();
+        parse(&Source::new("test.nl", text), &mut declared, diagnostics).map(|v| v.1)
+    }
+
+    fn parse_errs(text: &str, max_errors: usize) -> Vec<CompileError> {
+        parse_with(text, Diagnostics::new(max_errors))
+            .unwrap_err()
+            .errors
+    }
+
+    fn parse_err(text: &str) -> CompileError {
+        parse_errs(text, 1).remove(0)
+    }
+
+    #[test]
+    fn error_kinds() {
+        assert!(matches!(
+            parse_err("(+ 1 x)"),
+            CompileError::UnknownName { name, .. } if name == "x"
+        ));
+        assert!(matches!(
+            parse_err("(fn f (a a) a)"),
+            CompileError::Redeclaration { name, .. } if name == "a"
+        ));
+        assert!(matches!(
+            parse_err("(fn + (a b) a)"),
+            CompileError::Redeclaration { name, .. } if name == "+"
+        ));
+        assert!(matches!(
+            parse_err("(+ 1)"),
+            CompileError::Arity {
+                expected: 2,
+                found: 1,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_err("(+ 1 2 3)"),
+            CompileError::Arity {
+                expected: 2,
+                found: 3,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_err("(+ 1 '')"),
+            CompileError::MalformedLiteral {
+                kind: LiteralKind::Char,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn error_location() {
+        let err = parse_err("(+ 1\n   y)");
+        assert_eq!(err.code(), "E0003");
+        let at = err.location().unwrap();
+        assert_eq!(
+            (at.file.as_str(), at.span.line, at.span.col),
+            ("test.nl", 2, 4)
+        );
+    }
+
+    #[test]
+    fn int_literals() {
+        assert_eq!(parse_int("1_000_000"), Ok(1_000_000));
+        assert_eq!(parse_int("-42"), Ok(-42));
+        assert_eq!(parse_int("0xFF_FF"), Ok(0xFFFF));
+        assert_eq!(parse_int("0b1010"), Ok(10));
+        assert_eq!(parse_int("0o777"), Ok(0o777));
+        assert_eq!(parse_int("0xFFFFFFFF"), Ok(-1));
+        assert_eq!(parse_int("-2147483648"), Ok(i32::MIN));
+        assert_eq!(parse_int("4294967296"), Err(LiteralKind::Overflow));
+        assert_eq!(parse_int("-0x80000001"), Err(LiteralKind::Overflow));
+        assert_eq!(parse_int("0x"), Err(LiteralKind::Number));
+        assert_eq!(parse_int("0b102"), Err(LiteralKind::Number));
+        assert_eq!(parse_int("0x_1"), Err(LiteralKind::Number));
+        assert_eq!(parse_int("12ab"), Err(LiteralKind::Number));
+        assert!(matches!(
+            parse_err("(+ 1 99999999999)"),
+            CompileError::MalformedLiteral {
+                kind: LiteralKind::Overflow,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn recovery() {
+        let errs = parse_errs(
+            "(fn f (a)\n  (+ a x))\n(+ 1 (foo 2 3))\n(fn g (a a) (+ a 'bc'))\n(+ (+ 1 2\n(+ y 1)",
+            20,
+        );
+        let codes: Vec<(&str, usize)> = errs
+            .iter()
+            .map(|v| (v.code(), v.location().unwrap().span.line))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                ("E0003", 2),
+                ("E0003", 3),
+                ("E0004", 4),
+                ("E0002", 4),
+                ("E0005", 5),
+                ("E0003", 6),
+                ("E0001", 6),
+            ]
+        );
+        assert_eq!(parse_errs("(+ a b) (+ c d) (+ e f)", 2).len(), 2);
+
+        // the `(` where `)` was expected begins the next expression
+        let errs = parse_errs("(fn f (a) (+ a 1)\n(fn g (b) (+ b zz))\n(+ (h 1) 1)", 20);
+        let codes: Vec<(&str, usize)> = errs
+            .iter()
+            .map(|v| (v.code(), v.location().unwrap().span.line))
+            .collect();
+        assert_eq!(codes, vec![("E0001", 2), ("E0003", 2), ("E0003", 3)]);
+
+        // stray tokens and unclosed expressions among indented forms and
+        // several forms on a line
+        let errs = parse_errs(
+            "  (+ 1 2)) x (+ a 1)\n  (+ (+ 1 2\n  (+ b 1) (+ c 1)\n    (+ d 1)",
+            20,
+        );
+        let codes: Vec<(&str, usize, usize)> = errs
+            .iter()
+            .map(|v| {
+                let span = v.location().unwrap().span;
+                (v.code(), span.line, span.col)
+            })
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                ("E0001", 1, 10),
+                ("E0001", 1, 12),
+                ("E0003", 1, 17),
+                ("E0005", 2, 7),
+                ("E0003", 3, 6),
+                ("E0003", 3, 14),
+                ("E0003", 4, 8),
+                ("E0001", 4, 12),
+            ]
+        );
+    }
+
+    #[test]
+    fn lambda_captures() {
+        let text = "(let a 1 (let b 2 (lambda (c) (+ c (apply (lambda () (+ a 1)))))))";
+        let mut declared = Declared::new();
+        declared.fn_def("+", Signature::fixed(2)).unwrap();
+        let (expressions, _) = parse(
+            &Source::new("test.nl", text),
+            &mut declared,
+            Diagnostics::new(1),
+        )
+        .unwrap();
+        let Expression::VarDef { expr, .. } = &expressions[0] else {
+            panic!("let expected")
+        };
+        let Expression::VarDef { expr, .. } = expr.as_ref() else {
+            panic!("let expected")
+        };
+        assert!(matches!(
+            expr.as_ref(),
+            Expression::Lambda { captures, .. } if captures == &vec!["a".to_string()]
+        ));
+
+        assert!(matches!(
+            parse_err("(ref x)"),
+            CompileError::UnknownName { name, .. } if name == "x"
+        ));
+        assert!(matches!(
+            parse_err("(fn f (x) (ref x))"),
+            CompileError::UnknownName { name, .. } if name == "x"
+        ));
+    }
+
+    #[test]
+    fn ref_signatures() {
+        assert!(parse_with("(fn f (a b) (+ a b)) (ref f) (ref +)", Diagnostics::new(1)).is_ok());
+        assert!(matches!(
+            parse_err("(fn f (a &rest r) a) (ref f)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Ref,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_err("(fn f (a (b 5)) (+ a b)) (ref f)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Ref,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_err("(fn f (s:str) s) (apply (ref f) 42)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Ref,
+                ..
+            }
+        ));
+
+        let mut declared = Declared::new();
+        let plus = Signature {
+            rest: Rest::Fold,
+            ..Signature::fixed(2)
+        };
+        declared.fn_def("+", plus).unwrap();
+        let errors = parse(
+            &Source::new("test.nl", "(ref +)"),
+            &mut declared,
+            Diagnostics::new(1),
+        )
+        .unwrap_err()
+        .errors;
+        assert!(matches!(
+            &errors[..],
+            [CompileError::Syntax {
+                expected: ExpressionType::Ref,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn array_literals() {
+        assert!(parse_with("(+ 1 [1 -2 0x3 'a'])", Diagnostics::new(1)).is_ok());
+        assert!(matches!(
+            parse_err("(+ [1 x] 1)"),
+            CompileError::Syntax {
+                expected: ExpressionType::ArrayItem,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_err("(+ [1 2 1)"),
+            CompileError::Syntax {
+                expected: ExpressionType::ArrayItem,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_err("(+ 1 ])"),
+            CompileError::Syntax {
+                expected: ExpressionType::Expression,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn quoted_lists() {
+        let mut declared = Declared::new();
+        declared.fn_def("+", Signature::fixed(2)).unwrap();
+        let source = Source::new("test.nl", "(+ '(1 (x '(y)) ()) (quote 'a'))");
+        let (expressions, _) = parse(&source, &mut declared, Diagnostics::new(1)).unwrap();
+        let Expression::Fn { args, .. } = &expressions[0] else {
+            panic!("a call was expected");
+        };
+        let symbol = |v: &str| Datum::Symbol(v.to_string());
+        assert!(
+            matches!(&args[0], Expression::Quote(Datum::List(items)) if items == &[
+                Datum::Int(1),
+                Datum::List(vec![
+                    symbol("x"),
+                    Datum::List(vec![symbol("quote"), Datum::List(vec![symbol("y")])]),
+                ]),
+                Datum::List(Vec::new()),
+            ])
+        );
+        assert!(matches!(&args[1], Expression::Quote(Datum::Int(97))));
+        assert!(matches!(
+            parse_err("(+ '(1 \"a\") 1)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Datum,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn macros() {
+        let text = "(defmacro twice (x) `(+ ,x ,x))\n(defmacro sum ((a) a) ((a &rest r) `(+ ,a (sum ,@r))))\n(+ (twice 1) (sum 1 2 3))";
+        assert!(parse_with(text, Diagnostics::new(1)).is_ok());
+        assert!(matches!(
+            parse_err("(defmacro m (x) `(+ ,x ,y))"),
+            CompileError::UnknownName { name, .. } if name == "y"
+        ));
+        assert!(matches!(
+            parse_err("(defmacro m (x) (+ x 1))"),
+            CompileError::Syntax {
+                expected: ExpressionType::MacroBody,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_err("(defmacro m (x) x) (+ (m) 1)"),
+            CompileError::Arity { name, expected: 1, bound: Bound::Exactly, found: 0, .. } if name == "m"
+        ));
+        for (text, expected, bound) in [
+            ("(defmacro m (x &rest r) x) (m)", 1, Bound::AtLeast),
+            ("(defmacro m ((a) a) ((a b c) a)) (m)", 1, Bound::AtLeast),
+            (
+                "(defmacro m ((a) a) ((a b c) a)) (m 1 2 3 4)",
+                3,
+                Bound::AtMost,
+            ),
+            (
+                "(defmacro m ((a) a) ((a b c d) a)) (m 1 2)",
+                1,
+                Bound::Exactly,
+            ),
+            (
+                "(defmacro m ((a) a) ((a b c &rest d) a)) (m 1 2)",
+                3,
+                Bound::AtLeast,
+            ),
+        ] {
+            let error = parse_err(text);
+            assert!(
+                matches!(error, CompileError::Arity { expected: e, bound: b, .. } if e == expected && b == bound),
+                "{}: {}",
+                text,
+                error
+            );
+        }
+        assert!(matches!(
+            parse_err("(defmacro m (x) `(m ,x)) (+ (m 1) 1)"),
+            CompileError::MacroExpansion { name, .. } if name == "m"
+        ));
+        assert!(matches!(
+            parse_err("(+ 1 (defmacro m (x) x))"),
+            CompileError::Misplaced { form, .. } if form == "defmacro"
+        ));
+        assert!(matches!(
+            parse_err("(+ `(1) 1)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Expression,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn imports() {
+        let source = Source::new(
+            "test.nl",
+            "(import \"a.nl\")\n(import \"b.nl\" b)\n(+ 1 (import \"c.nl\"))",
+        );
+        let found: Vec<_> = super::imports(&source)
+            .into_iter()
+            .map(|v| (v.path, v.namespace))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("a.nl".to_string(), None),
+                ("b.nl".to_string(), Some("b".to_string()))
+            ]
+        );
+        assert!(matches!(
+            parse_err("(+ 1 (import \"a.nl\"))"),
+            CompileError::Misplaced { form, .. } if form == "import"
+        ));
+        assert!(matches!(
+            parse_err("(import a)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Import,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn forward_references() {
+        let text = "(fn f (x) (g x 1))\n(+ (g 1 2) (f 3))\n(fn g (a b) (+ a (f b)))";
+        assert!(parse_with(text, Diagnostics::new(1)).is_ok());
+        assert!(matches!(
+            parse_err("(g 1)\n(fn g (a b) a)"),
+            CompileError::Arity { name, expected: 2, found: 1, .. } if name == "g"
+        ));
+        assert!(matches!(
+            parse_err("(fn g () 1)\n(fn g () 2)"),
+            CompileError::Redeclaration { name, at: Some(at) } if name == "g" && at.span.line == 2
+        ));
+    }
+
+    #[test]
+    fn variadic() {
+        let text = "(fn f (a (b 2) (c 'c') &rest xs) a)\n(+ (f 1) (f 1 2 3 4 5))";
+        assert!(parse_with(text, Diagnostics::new(1)).is_ok());
+        assert!(matches!(
+            parse_err("(fn f (a b &rest xs) a)\n(f 1)"),
+            CompileError::Arity { name, expected: 2, bound: Bound::AtLeast, found: 1, .. } if name == "f"
+        ));
+        assert!(matches!(
+            parse_err("(fn f (a (b 2)) a)\n(f 1 2 3)"),
+            CompileError::Arity { name, expected: 2, bound: Bound::AtMost, found: 3, .. } if name == "f"
+        ));
+        for params in ["((a 1) b)", "(a &rest)", "(&rest a b)", "(&rest (a 1))"] {
+            assert!(matches!(
+                parse_err(&format!("(fn f {} 0)", params)),
+                CompileError::Syntax {
+                    expected: ExpressionType::Args,
+                    ..
+                }
+            ));
+        }
+        assert!(matches!(
+            parse_err("(fn f ((a b)) a)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Default,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_err("(lambda (a &rest b) a)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Args,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn logic() {
+        let text = "(+ (and 1 (or 0 2) 3) (cond ((and 1 0) 1) (1 2) (else 3)))";
+        assert!(parse_with(text, Diagnostics::new(1)).is_ok());
+        assert!(matches!(
+            parse_err("(and)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Expression,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_err("(cond 1 2)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Clause,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_err("(cond (else 1) (1 2))"),
+            CompileError::Syntax {
+                expected: ExpressionType::Close,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn switch() {
+        let text = "(+ 1 (switch 2 (1 10) ((2 'a') 20) (() 30) (else 40)))";
+        assert!(parse_with(text, Diagnostics::new(1)).is_ok());
+        for text in [
+            "(switch 1 (1 10) (1 20))",
+            "(switch 1 ((1 2) 10) ((3 1) 20))",
+            "(switch 1 (x 1))",
+        ] {
+            assert!(matches!(
+                parse_err(text),
+                CompileError::Syntax {
+                    expected: ExpressionType::SwitchKey,
+                    ..
+                }
+            ));
+        }
+        assert!(matches!(
+            parse_err("(switch 1 2)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Clause,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn try_throw() {
+        let text = "(fn f (n) (try (throw n) (catch e (+ e 1)))) (try (f 1) (catch e (throw e)))";
+        assert!(parse_with(text, Diagnostics::new(1)).is_ok());
+        for text in [
+            "(try 1)",
+            "(try 1 2)",
+            "(try 1 (handle e 2))",
+            "(try 1 (catch (e) 2))",
+        ] {
+            assert!(matches!(
+                parse_err(text),
+                CompileError::Syntax {
+                    expected: ExpressionType::Catch,
+                    ..
+                }
+            ));
+        }
+        assert!(matches!(
+            parse_err("(try 1 (catch e e) 2)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Close,
+                ..
+            }
+        ));
     }
 
-    Ok(expressions)
+    #[test]
+    fn annotations() {
+        let text = "(fn f (a:int (b:str \"\") &rest c:ptr) (let d:char 'x' (+ a d)))";
+        assert!(parse_with(text, Diagnostics::new(1)).is_ok());
+        assert!(parse_with("(fn f (&rest a:int) a)", Diagnostics::new(1)).is_ok());
+        for text in ["(fn f (a:num) a)", "(let :int 1 2)", "(let a: 1 a)"] {
+            assert!(matches!(
+                parse_err(text),
+                CompileError::Syntax {
+                    expected: ExpressionType::Type,
+                    ..
+                }
+            ));
+        }
+        assert!(matches!(
+            parse_err("(lambda (a:int) a)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Args,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn structs() {
+        let mut declared = Declared::new();
+        let source = Source::new("test.nl", "(struct point x y) (set_point_y (point 1 2) 3)");
+        let (expressions, _) = parse(&source, &mut declared, Diagnostics::new(1)).unwrap();
+        let Expression::Field {
+            object,
+            offset: 4,
+            value: Some(_),
+            ..
+        } = &expressions[1]
+        else {
+            panic!("not a field access: {:?}", expressions[1]);
+        };
+        assert!(matches!(object.as_ref(), Expression::Fn { name, .. } if name == "point"));
+        for name in ["point", "point_x", "point_y", "set_point_x", "set_point_y"] {
+            assert!(declared.fns.contains_key(name));
+        }
+
+        assert!(matches!(
+            parse_err("(struct point x y x)"),
+            CompileError::Redeclaration { name, .. } if name == "x"
+        ));
+        assert!(matches!(
+            parse_err("(struct point x) (struct point y)"),
+            CompileError::Redeclaration { name, .. } if name == "point"
+        ));
+        assert!(matches!(
+            parse_err("(struct a b_c) (struct a_b c)"),
+            CompileError::Redeclaration { name, .. } if name == "a_b_c"
+        ));
+        assert!(matches!(
+            parse_err("(+ 1 (struct point x))"),
+            CompileError::Misplaced { form, .. } if form == "struct"
+        ));
+        assert!(matches!(
+            parse_err("(struct point)"),
+            CompileError::Syntax {
+                expected: ExpressionType::Struct,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_err("(struct point x) (point_x 1 2)"),
+            CompileError::Arity {
+                expected: 1,
+                found: 2,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn globals() {
+        assert!(parse_with("(def a 1) (fn f (x) (set a (+ a x)))", Diagnostics::new(1)).is_ok());
+        assert!(matches!(
+            parse_err("(+ 1 (def a 1))"),
+            CompileError::Misplaced { form, .. } if form == "def"
+        ));
+        assert!(matches!(
+            parse_err("(fn f (x) (def a x))"),
+            CompileError::Misplaced { form, .. } if form == "def"
+        ));
+        assert!(matches!(
+            parse_err("(def + 1)"),
+            CompileError::Redeclaration { name, .. } if name == "+"
+        ));
+        assert!(matches!(
+            parse_err("(def a a)"),
+            CompileError::UnknownName { name, .. } if name == "a"
+        ));
+    }
+
+    #[test]
+    fn shadowing() {
+        let text = "(fn f (+) (let + 1 (for + (let + 2 +) 0 +)))";
+        assert_eq!(parse_with(text, Diagnostics::new(20)).unwrap().len(), 0);
+
+        let diagnostics = Diagnostics {
+            warn_shadowing: true,
+            ..Diagnostics::new(20)
+        };
+        let warnings = parse_with(text, diagnostics).unwrap();
+        let columns: Vec<usize> = warnings.iter().map(|v| v.location().span.col).collect();
+        assert_eq!(columns, vec![8, 16, 25, 32]);
+        assert_eq!(parse_errs("(let a 1 a) a", 20).len(), 1);
+    }
+
+    #[test]
+    fn misplaced_jumps() {
+        assert!(matches!(
+            parse_err("(break)"),
+            CompileError::Misplaced { form, .. } if form == "break"
+        ));
+        assert!(matches!(
+            parse_err("(fn f (a) (continue))"),
+            CompileError::Misplaced { form, .. } if form == "continue"
+        ));
+        assert!(matches!(
+            parse_err("(while 1 (return 1))"),
+            CompileError::Misplaced { form, .. } if form == "return"
+        ));
+        assert!(matches!(
+            parse_err("(while 1 (fn f (a) (break)))"),
+            CompileError::Misplaced { form, .. } if form == "break"
+        ));
+        assert_eq!(parse_errs("(while (break) 0) (break)", 20).len(), 1);
+    }
 }
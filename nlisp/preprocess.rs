@@ -3,6 +3,8 @@ use crate::util::expression::Expression;
 #[derive(Debug)]
 pub struct Preprocessed {
     pub fn_defs: Vec<Expression>,
+    /// `def` expressions in the order of declaration.
+    pub globals: Vec<Expression>,
     pub main: Vec<Expression>,
 }
 
@@ -11,17 +13,45 @@ fn preprocess_expr(expression: Expression, preprocessed: &mut Preprocessed) -> E
         Expression::FnDef {
             name,
             arguments,
+            captures,
             expr,
         } => {
             let expr = Box::new(preprocess_expr(*expr, preprocessed));
             preprocessed.fn_defs.push(Expression::FnDef {
                 name,
                 arguments,
+                captures,
                 expr,
             });
 
             Expression::Value(0)
         }
+        Expression::Lambda {
+            arguments,
+            captures,
+            expr,
+        } => {
+            let expr = Box::new(preprocess_expr(*expr, preprocessed));
+            // A space can't appear in a name, so it never clashes with user's
+            let name = format!("lambda {}", preprocessed.fn_defs.len());
+            preprocessed.fn_defs.push(Expression::FnDef {
+                name: name.clone(),
+                arguments,
+                captures: captures.clone(),
+                expr,
+            });
+
+            Expression::Closure { name, captures }
+        }
+        Expression::Apply { f, args } => {
+            let f = Box::new(preprocess_expr(*f, preprocessed));
+            let args = args
+                .into_iter()
+                .map(|v| preprocess_expr(v, preprocessed))
+                .collect();
+
+            Expression::Apply { f, args }
+        }
         Expression::Case { condition, t, f } => {
             let condition = Box::new(preprocess_expr(*condition, preprocessed));
             let t = Box::new(preprocess_expr(*t, preprocessed));
@@ -46,20 +76,124 @@ fn preprocess_expr(expression: Expression, preprocessed: &mut Preprocessed) -> E
                 expr,
             }
         }
-        Expression::Fn { name, args } => {
+        Expression::While { condition, expr } => {
+            let condition = Box::new(preprocess_expr(*condition, preprocessed));
+            let expr = Box::new(preprocess_expr(*expr, preprocessed));
+
+            Expression::While { condition, expr }
+        }
+        Expression::Fn { name, args, at } => {
             let args: Vec<Expression> = args
                 .into_iter()
                 .map(|v| preprocess_expr(v, preprocessed))
                 .collect();
 
-            Expression::Fn { name, args }
+            Expression::Fn { name, args, at }
         }
-        Expression::VarDef { name, init, expr } => {
+        Expression::Rest(args) => Expression::Rest(
+            args.into_iter()
+                .map(|v| preprocess_expr(v, preprocessed))
+                .collect(),
+        ),
+        Expression::VarDef {
+            name,
+            ty,
+            init,
+            expr,
+            at,
+        } => {
             let init = Box::new(preprocess_expr(*init, preprocessed));
             let expr = Box::new(preprocess_expr(*expr, preprocessed));
 
-            Expression::VarDef { name, init, expr }
+            Expression::VarDef {
+                name,
+                ty,
+                init,
+                expr,
+                at,
+            }
+        }
+        Expression::Set { name, expr, at } => {
+            let expr = Box::new(preprocess_expr(*expr, preprocessed));
+
+            Expression::Set { name, expr, at }
         }
+        Expression::Def { name, init } => {
+            let init = Box::new(preprocess_expr(*init, preprocessed));
+            preprocessed.globals.push(Expression::Def { name, init });
+
+            Expression::Value(0)
+        }
+        Expression::Return(expr) => {
+            Expression::Return(Box::new(preprocess_expr(*expr, preprocessed)))
+        }
+        Expression::Do(exprs) => {
+            let exprs: Vec<Expression> = exprs
+                .into_iter()
+                .map(|v| preprocess_expr(v, preprocessed))
+                .collect();
+
+            Expression::Do(exprs)
+        }
+        Expression::Switch {
+            expr,
+            clauses,
+            default,
+        } => {
+            let expr = Box::new(preprocess_expr(*expr, preprocessed));
+            let clauses = clauses
+                .into_iter()
+                .map(|(keys, v)| (keys, preprocess_expr(v, preprocessed)))
+                .collect();
+            let default = Box::new(preprocess_expr(*default, preprocessed));
+
+            Expression::Switch {
+                expr,
+                clauses,
+                default,
+            }
+        }
+        Expression::Field {
+            name,
+            object,
+            offset,
+            value,
+            at,
+        } => {
+            let object = Box::new(preprocess_expr(*object, preprocessed));
+            let value = value.map(|v| Box::new(preprocess_expr(*v, preprocessed)));
+
+            Expression::Field {
+                name,
+                object,
+                offset,
+                value,
+                at,
+            }
+        }
+        Expression::Try { body, var, handler } => {
+            let body = Box::new(preprocess_expr(*body, preprocessed));
+            let handler = Box::new(preprocess_expr(*handler, preprocessed));
+
+            Expression::Try { body, var, handler }
+        }
+        Expression::Throw { code, at } => {
+            let code = Box::new(preprocess_expr(*code, preprocessed));
+
+            Expression::Throw { code, at }
+        }
+        Expression::And(exprs) => Expression::And(
+            exprs
+                .into_iter()
+                .map(|v| preprocess_expr(v, preprocessed))
+                .collect(),
+        ),
+        Expression::Or(exprs) => Expression::Or(
+            exprs
+                .into_iter()
+                .map(|v| preprocess_expr(v, preprocessed))
+                .collect(),
+        ),
         _ => expression,
     }
 }
@@ -67,6 +201,7 @@ fn preprocess_expr(expression: Expression, preprocessed: &mut Preprocessed) -> E
 pub fn preprocess(program: Vec<Expression>) -> Preprocessed {
     let mut preprocessed = Preprocessed {
         fn_defs: Vec::new(),
+        globals: Vec::new(),
         main: Vec::new(),
     };
     let program: Vec<Expression> = program
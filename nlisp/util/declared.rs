@@ -1,57 +1,228 @@
 use std::collections::{HashMap, HashSet};
 
+use crate::{
+    error::CompileError,
+    util::{expression::Expression, macros::MacroRule, types::Type},
+};
+
+/// What a function does with the arguments after its last parameter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rest {
+    /// There must be none.
+    None,
+    /// They are passed to the `&rest` parameter as an array.
+    Array,
+    /// Calls of an associative built-in are nested instead, so that
+    /// `(+ a b c)` is `(+ (+ a b) c)`.
+    Fold,
+}
+
+/// Parameters of a function, against which its calls are checked.
+#[derive(Debug, Clone)]
+pub struct Signature {
+    /// Number of parameters, including the optional ones but not `&rest`.
+    pub params: usize,
+    /// Values of the trailing optional parameters, put in place of the
+    /// missing arguments.
+    pub defaults: Vec<Expression>,
+    pub rest: Rest,
+    /// Types of the parameters, not including `&rest`, which is always a
+    /// `ptr`.
+    pub types: Vec<Type>,
+    /// Type of each argument passed to `&rest`.
+    pub rest_type: Type,
+    /// Declared type of the result. The checker infers it for functions
+    /// defined in code.
+    pub result: Type,
+}
+
+impl Signature {
+    pub fn fixed(params: usize) -> Self {
+        Signature {
+            params,
+            defaults: Vec::new(),
+            rest: Rest::None,
+            types: vec![Type::Any; params],
+            rest_type: Type::Any,
+            result: Type::Any,
+        }
+    }
+
+    /// Number of arguments that can't be omitted.
+    pub fn required(&self) -> usize {
+        self.params - self.defaults.len()
+    }
+}
+
+/// Function generated for a struct field, whose calls access the field in
+/// place.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Accessor {
+    /// Reads the field at the offset in bytes.
+    Get(u16),
+    /// Writes the field at the offset in bytes.
+    Set(u16),
+}
+
 #[derive(Debug)]
 pub struct Declared {
-    pub vars: HashSet<String>,
-    pub fns: HashMap<String, usize>,
+    /// Variables in scope, innermost last. Inner variables may shadow outer
+    /// ones, so a name can appear more than once.
+    pub vars: Vec<String>,
+    pub fns: HashMap<String, Signature>,
+    /// Top-level functions declared ahead of their definitions, so that they
+    /// can be called before them.
+    pub forward: HashSet<String>,
+    /// Global variables declared with `def`, visible everywhere after it.
+    pub globals: HashSet<String>,
+    /// Macros declared with `defmacro`, expanded while parsing.
+    pub macros: HashMap<String, Vec<MacroRule>>,
+    /// Field accessors and setters of the structs declared with `struct`.
+    pub accessors: HashMap<String, Accessor>,
+    /// Namespace of the module being parsed: its functions, globals and
+    /// macros are declared as `namespace/name`.
+    pub namespace: Option<String>,
+    pub in_fn: bool,
+    /// Whether the expression being parsed is a top-level one.
+    pub top_level: bool,
+    /// Number of loops enclosing the code being parsed.
+    pub loops: usize,
+    /// Variables referenced so far; a lambda captures those of them that are
+    /// declared outside of it.
+    pub used: HashSet<String>,
 }
 
 impl Declared {
     pub fn new() -> Self {
         Declared {
-            vars: HashSet::new(),
+            vars: Vec::new(),
             fns: HashMap::new(),
+            forward: HashSet::new(),
+            globals: HashSet::new(),
+            macros: HashMap::new(),
+            accessors: HashMap::new(),
+            namespace: None,
+            in_fn: false,
+            top_level: false,
+            loops: 0,
+            used: HashSet::new(),
+        }
+    }
+
+    pub fn is_var(&self, name: &str) -> bool {
+        self.vars.iter().any(|v| v == name)
+    }
+
+    /// Name under which a definition in the current module is declared.
+    pub fn qualify(&self, name: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}/{}", namespace, name),
+            None => name.to_string(),
         }
     }
 
-    pub fn assert_undeclared(&self, name: &str) -> Result<(), String> {
-        if self.vars.contains(name) || self.fns.contains_key(name) {
-            Err(format!(
-                "Variable or function name '{}' is already declared",
-                name
-            ))
+    /// Name a reference to a function, global or macro refers to: the
+    /// module's own definition wins over the one outside of it.
+    pub fn resolve(&self, name: &str) -> String {
+        let qualified = self.qualify(name);
+        if self.fns.contains_key(&qualified)
+            || self.globals.contains(&qualified)
+            || self.macros.contains_key(&qualified)
+        {
+            qualified
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// Functions, globals and macros share one namespace and can't be
+    /// redeclared.
+    pub fn assert_undeclared(&self, name: &str) -> Result<(), CompileError> {
+        if self.fns.contains_key(name)
+            || self.globals.contains(name)
+            || self.macros.contains_key(name)
+        {
+            Err(CompileError::Redeclaration {
+                name: name.to_string(),
+                at: None,
+            })
         } else {
             Ok(())
         }
     }
 
-    pub fn fn_def(&mut self, fn_name: &str, args: usize) -> Result<(), String> {
+    pub fn fn_def(&mut self, fn_name: &str, signature: Signature) -> Result<(), CompileError> {
         self.assert_undeclared(fn_name)?;
-        self.fns.insert(fn_name.to_string(), args);
+        self.fns.insert(fn_name.to_string(), signature);
         Ok(())
     }
 
-    pub fn var_dec(&mut self, var_name: &str) -> Result<(), String> {
-        self.assert_undeclared(var_name)?;
-        self.vars.insert(var_name.to_string());
+    pub fn global_def(&mut self, name: &str) -> Result<(), CompileError> {
+        self.assert_undeclared(name)?;
+        self.globals.insert(name.to_string());
         Ok(())
     }
 
-    pub fn var_undec(&mut self, var_name: &str) -> Result<(), String> {
-        if self.vars.remove(var_name) {
-            Ok(())
+    pub fn macro_def(&mut self, name: &str, rules: Vec<MacroRule>) -> Result<(), CompileError> {
+        self.assert_undeclared(name)?;
+        self.macros.insert(name.to_string(), rules);
+        Ok(())
+    }
+
+    /// Macro called by the name, unless a variable shadows it.
+    pub fn macro_rules(&self, name: &str) -> Option<&Vec<MacroRule>> {
+        if self.is_var(name) {
+            None
         } else {
-            Err(format!(
-                "Undeclaring variable '{}' that did not declare",
-                &var_name
-            ))
+            self.macros.get(&self.resolve(name))
+        }
+    }
+
+    /// Declares the variable. Returns whether it shadows a variable, a global
+    /// or a function with the same name.
+    pub fn var_dec(&mut self, var_name: &str) -> bool {
+        let shadows = self.is_var(var_name)
+            || self.globals.contains(var_name)
+            || self.fns.contains_key(var_name);
+        self.vars.push(var_name.to_string());
+        shadows
+    }
+
+    pub fn var_undec(&mut self, var_name: &str) -> Result<(), CompileError> {
+        match self.vars.iter().rposition(|v| v == var_name) {
+            Some(idx) => {
+                self.vars.remove(idx);
+                Ok(())
+            }
+            None => Err(CompileError::UnknownName {
+                name: var_name.to_string(),
+                at: None,
+            }),
         }
     }
 
     pub fn novar(&self) -> Self {
         Declared {
-            vars: HashSet::new(),
+            vars: Vec::new(),
             fns: self.fns.clone(),
+            forward: HashSet::new(),
+            globals: self.globals.clone(),
+            macros: self.macros.clone(),
+            accessors: self.accessors.clone(),
+            namespace: self.namespace.clone(),
+            in_fn: true,
+            top_level: false,
+            loops: 0,
+            used: HashSet::new(),
+        }
+    }
+
+    /// Scope of a lambda body: like a function, but outer variables stay
+    /// visible.
+    pub fn lambda(&self) -> Self {
+        Declared {
+            vars: self.vars.clone(),
+            ..self.novar()
         }
     }
 }
@@ -1,12 +1,36 @@
-use crate::util::error_code;
+use crate::{
+    error::{CompileError, Location},
+    util::{
+        source::{Source, Span},
+        types::Type,
+    },
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Expression {
     FnDef {
         name: String,
         arguments: Vec<String>,
+        /// Variables copied into the closure environment; empty for named
+        /// functions.
+        captures: Vec<String>,
+        expr: Box<Expression>,
+    },
+    Lambda {
+        arguments: Vec<String>,
+        captures: Vec<String>,
         expr: Box<Expression>,
     },
+    /// Callable value of the function `name`. Lambdas become closures during
+    /// preprocessing.
+    Closure {
+        name: String,
+        captures: Vec<String>,
+    },
+    Apply {
+        f: Box<Expression>,
+        args: Vec<Expression>,
+    },
     Case {
         condition: Box<Expression>,
         t: Box<Expression>,
@@ -18,51 +42,152 @@ pub enum Expression {
         while_expr: Box<Expression>,
         expr: Box<Expression>,
     },
+    While {
+        condition: Box<Expression>,
+        expr: Box<Expression>,
+    },
+    /// Call of a function. The arguments of a variadic one end with `Rest`.
     Fn {
         name: String,
         args: Vec<Expression>,
+        /// The call, for type errors in its arguments.
+        at: Location,
     },
+    /// Extra arguments of a variadic call, passed as an array.
+    Rest(Vec<Expression>),
     VarDef {
+        name: String,
+        /// Type annotation of the variable, if any.
+        ty: Option<Type>,
+        init: Box<Expression>,
+        expr: Box<Expression>,
+        at: Location,
+    },
+    Set {
+        name: String,
+        expr: Box<Expression>,
+        at: Location,
+    },
+    Def {
         name: String,
         init: Box<Expression>,
+    },
+    Break,
+    Continue,
+    Return(Box<Expression>),
+    Do(Vec<Expression>),
+    /// Evaluates the branch whose keys include the value of `expr`, or the
+    /// default one.
+    Switch {
         expr: Box<Expression>,
+        clauses: Vec<(Vec<i32>, Expression)>,
+        default: Box<Expression>,
+    },
+    /// Reads the word `offset` bytes into the struct `object` points to or,
+    /// with `value`, writes it. `name` is the accessor called.
+    Field {
+        name: String,
+        object: Box<Expression>,
+        offset: u16,
+        value: Option<Box<Expression>>,
+        at: Location,
     },
+    /// Evaluates `body`; if it throws, binds the thrown code to `var` and
+    /// evaluates `handler` instead.
+    Try {
+        body: Box<Expression>,
+        var: String,
+        handler: Box<Expression>,
+    },
+    /// Unwinds the stack to the innermost enclosing `try` and passes it the
+    /// code.
+    Throw {
+        code: Box<Expression>,
+        at: Location,
+    },
+    /// Evaluates the expressions until one is 0; the value is the last one
+    /// evaluated.
+    And(Vec<Expression>),
+    /// Evaluates the expressions until one is not 0; the value is the last
+    /// one evaluated.
+    Or(Vec<Expression>),
     Var(String),
-    Str(String),
+    Str(Vec<u8>),
+    Array(Vec<i32>),
+    Quote(Datum),
     Value(i32),
 }
 
+/// Quoted data, laid out in static memory as cons cells and symbols.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Datum {
+    Int(i32),
+    Symbol(String),
+    List(Vec<Datum>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ExpressionType {
-    Char,
-    Number,
-    Literal,
+    Expression,
+    Open,
+    Close,
     Args,
     FnDef,
     For,
     FnOrVar,
     VarDef,
+    Set,
+    Ref,
+    Def,
+    ArrayItem,
+    Datum,
+    Defmacro,
+    MacroBody,
+    Import,
+    Default,
+    Clause,
+    SwitchKey,
+    Type,
+    Struct,
+    Catch,
 }
 
 impl ExpressionType {
-    pub fn to_explained_string(&self, s: &str) -> String {
+    pub fn description(&self) -> &'static str {
         match self {
-            Self::Char => format!("A character was expected here: {}...", error_code(s)),
-            Self::Number => format!("A number was expected here: {}...", error_code(s)),
-            Self::Literal => format!("A literal was expected here: {}...", error_code(s)),
-            Self::Args => format!("An arguments were expected here: {}...", error_code(s)),
-            Self::FnDef => format!(
-                "A function definiton was expected here: {}...",
-                error_code(s)
-            ),
-            Self::For => format!("A for cycle was expected here: {}...", error_code(s)),
-            Self::FnOrVar => format!(
-                "A function call or variable reference was expected here: {}...",
-                error_code(s)
-            ),
-            Self::VarDef => format!(
-                "A variable definition was expected here: {}...",
-                error_code(s)
-            ),
+            Self::Expression => "An expression was expected here",
+            Self::Open => "'(' was expected here",
+            Self::Close => "')' was expected here",
+            Self::Args => "An arguments were expected here",
+            Self::FnDef => "A function definiton was expected here",
+            Self::For => "A for cycle was expected here",
+            Self::FnOrVar => "A function call or variable reference was expected here",
+            Self::VarDef => "A variable definition was expected here",
+            Self::Set => "A variable assignment was expected here",
+            Self::Ref => {
+                "A name of a function without optional, &rest or typed parameters was expected here"
+            }
+            Self::Def => "A global variable definition was expected here",
+            Self::ArrayItem => "A number, a character or ']' was expected here",
+            Self::Datum => "A number, a character, a name or a list was expected here",
+            Self::Defmacro => "A macro definition was expected here",
+            Self::MacroBody => "A quasiquote or a macro parameter was expected here",
+            Self::Import => "A module path was expected here",
+            Self::Default => "A number, a character or a string was expected here",
+            Self::Clause => "A '(condition expression)' clause was expected here",
+            Self::SwitchKey => {
+                "A number or a character not used in the other clauses was expected here"
+            }
+            Self::Type => "A name with a type int, char, str, ptr or any was expected here",
+            Self::Struct => "A struct declaration was expected here",
+            Self::Catch => "A '(catch name expression)' clause was expected here",
+        }
+    }
+
+    pub fn to_error(self, source: &Source, span: Span) -> CompileError {
+        CompileError::Syntax {
+            expected: self,
+            at: Some(source.locate(span)),
         }
     }
 }
@@ -0,0 +1,106 @@
+use crate::{
+    error::Bound,
+    lexer::{Token, TokenKind},
+    util::source::Span,
+};
+
+/// One `((params) template)` clause of a `defmacro`. The template is the
+/// quasiquoted body without its backquote; a body that is just a parameter is
+/// kept as `,param`.
+#[derive(Debug, Clone)]
+pub struct MacroRule {
+    pub params: Vec<String>,
+    /// Parameter after `&rest`, which takes all the remaining arguments.
+    pub rest: Option<String>,
+    pub template: Vec<Token>,
+}
+
+impl MacroRule {
+    pub fn accepts(&self, args: usize) -> bool {
+        match self.rest {
+            Some(_) => args >= self.params.len(),
+            None => args == self.params.len(),
+        }
+    }
+
+    /// Argument count a call with `args` arguments is told to have when no
+    /// clause accepts it: the closest counts the clauses accept. Between two
+    /// accepted counts the nearest one is reported.
+    pub fn arity(rules: &[MacroRule], args: usize) -> (usize, Bound) {
+        let below = rules
+            .iter()
+            .map(|v| v.params.len())
+            .filter(|v| *v < args)
+            .max();
+        let above = rules
+            .iter()
+            .filter(|v| v.params.len() > args)
+            .min_by_key(|v| v.params.len());
+        // a single clause without `&rest` accepts just one count
+        let single = rules
+            .iter()
+            .all(|v| v.rest.is_none() && v.params.len() == rules[0].params.len());
+
+        match (below, above) {
+            (_, Some(rule)) if single => (rule.params.len(), Bound::Exactly),
+            (Some(below), _) if single => (below, Bound::Exactly),
+            (None, Some(rule)) => (rule.params.len(), Bound::AtLeast),
+            (Some(below), None) => (below, Bound::AtMost),
+            (Some(below), Some(rule)) if args - below < rule.params.len() - args => {
+                (below, Bound::Exactly)
+            }
+            (_, Some(rule)) if rule.rest.is_some() => (rule.params.len(), Bound::AtLeast),
+            (_, Some(rule)) => (rule.params.len(), Bound::Exactly),
+            (None, None) => unreachable!("a count no clause accepts is below or above one"),
+        }
+    }
+
+    pub fn is_param(&self, name: &str) -> bool {
+        self.params.iter().any(|v| v == name) || self.rest.as_deref() == Some(name)
+    }
+
+    /// Substitutes the arguments into the template. Tokens of the template
+    /// itself get the span of the call, so that errors in the expansion point
+    /// at it rather than at the macro definition, possibly in another file.
+    pub fn expand(&self, args: &[Vec<Token>], span: Span) -> Vec<Token> {
+        let rest = &args[self.params.len().min(args.len())..];
+        let token = |kind| Token { kind, span };
+        let mut expansion = Vec::new();
+        let mut template = self.template.iter();
+        while let Some(current) = template.next() {
+            let splice = match current.kind {
+                TokenKind::Unquote => false,
+                TokenKind::UnquoteSplicing => true,
+                _ => {
+                    expansion.push(token(current.kind.clone()));
+                    continue;
+                }
+            };
+            let Some(TokenKind::Atom(name)) = template.next().map(|v| &v.kind) else {
+                continue;
+            };
+            if self.rest.as_ref() == Some(name) {
+                if !splice {
+                    expansion.push(token(TokenKind::Open));
+                }
+                rest.iter().for_each(|v| expansion.extend_from_slice(v));
+                if !splice {
+                    expansion.push(token(TokenKind::Close));
+                }
+            } else if let Some(idx) = self.params.iter().position(|v| v == name) {
+                let arg = &args[idx];
+                // splicing a parenthesized argument inserts its items
+                let grouped = arg.len() > 1
+                    && arg[0].kind == TokenKind::Open
+                    && arg[arg.len() - 1].kind == TokenKind::Close;
+                if splice && grouped {
+                    expansion.extend_from_slice(&arg[1..arg.len() - 1]);
+                } else {
+                    expansion.extend_from_slice(arg);
+                }
+            }
+        }
+
+        expansion
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{CompileError, Diagnostics, ImportProblem, Warning},
+    parser::{imports, parse, Import},
+    util::{declared::Declared, expression::Expression, source::Source},
+};
+
+/// Parses a program together with the modules it imports. A module is parsed
+/// once, before the first file that imports it, so its definitions are
+/// declared by the time the importing file is parsed.
+pub struct Modules {
+    /// Directories searched after the one of the importing file.
+    pub search_paths: Vec<PathBuf>,
+    pub max_errors: usize,
+    pub warn_shadowing: bool,
+    /// Namespaces of the parsed modules.
+    loaded: HashMap<PathBuf, Option<String>>,
+    /// Files being parsed, outermost first.
+    loading: Vec<PathBuf>,
+    /// Top-level expressions of all files, modules before their importers.
+    pub expressions: Vec<Expression>,
+    pub warnings: Vec<Warning>,
+}
+
+impl Modules {
+    pub fn new(search_paths: Vec<PathBuf>, max_errors: usize, warn_shadowing: bool) -> Self {
+        Modules {
+            search_paths,
+            max_errors,
+            warn_shadowing,
+            loaded: HashMap::new(),
+            loading: Vec::new(),
+            expressions: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Parses the file at `path` with the given source text, after the
+    /// modules it imports.
+    pub fn load(
+        &mut self,
+        path: &Path,
+        source: &Source,
+        namespace: Option<String>,
+        declared: &mut Declared,
+    ) -> Result<(), Diagnostics> {
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        self.loading.push(canonical.clone());
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for import in imports(source) {
+            let error = |problem| CompileError::Import {
+                path: import.path.as_str().into(),
+                problem,
+                at: Some(source.locate(import.span)),
+            };
+            let module_path = self
+                .find(dir, &import)
+                .ok_or(error(ImportProblem::NotFound))?;
+            let module = fs::canonicalize(&module_path).unwrap_or(module_path.clone());
+            if let Some(idx) = self.loading.iter().position(|v| *v == module) {
+                let chain = self.loading[idx..]
+                    .iter()
+                    .chain([&module])
+                    .map(|v| v.file_name().unwrap_or_default().to_string_lossy().into())
+                    .collect();
+                return Err(error(ImportProblem::Cycle(chain)).into());
+            }
+            match self.loaded.get(&module) {
+                Some(loaded) if *loaded == import.namespace => continue,
+                Some(_) => return Err(error(ImportProblem::Namespace).into()),
+                None => (),
+            }
+
+            let text =
+                fs::read_to_string(&module_path).map_err(|_| error(ImportProblem::NotFound))?;
+            let module_source = Source::new(&module_path.to_string_lossy(), &text);
+            self.load(&module_path, &module_source, import.namespace, declared)?;
+        }
+
+        declared.namespace = namespace.clone();
+        let diagnostics = Diagnostics {
+            warn_shadowing: self.warn_shadowing,
+            ..Diagnostics::new(self.max_errors)
+        };
+        let parsed = parse(source, declared, diagnostics);
+        declared.namespace = None;
+        let (mut expressions, mut warnings) = parsed?;
+        self.expressions.append(&mut expressions);
+        self.warnings.append(&mut warnings);
+
+        self.loading.pop();
+        self.loaded.insert(canonical, namespace);
+
+        Ok(())
+    }
+
+    /// Looks for the module next to the importing file, then in the search
+    /// paths in order.
+    fn find(&self, dir: &Path, import: &Import) -> Option<PathBuf> {
+        std::iter::once(dir)
+            .chain(self.search_paths.iter().map(|v| v.as_path()))
+            .map(|v| v.join(&import.path))
+            .find(|v| v.is_file())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::Modules;
+    use crate::{
+        error::{CompileError, ImportProblem},
+        util::{declared::Declared, source::Source},
+    };
+
+    fn load_err(text: &str) -> CompileError {
+        let mut modules = Modules::new(vec![PathBuf::from("tests/modules")], 1, false);
+        let source = Source::new("tests/test.nl", text);
+        modules
+            .load(
+                Path::new("tests/test.nl"),
+                &source,
+                None,
+                &mut Declared::new(),
+            )
+            .unwrap_err()
+            .errors
+            .remove(0)
+    }
+
+    #[test]
+    fn import_errors() {
+        assert!(matches!(
+            load_err("(import \"missing.nl\")"),
+            CompileError::Import { path, problem: ImportProblem::NotFound, .. } if &*path == "missing.nl"
+        ));
+        let error = load_err("(import \"cycle_a.nl\")");
+        assert!(matches!(
+            &error,
+            CompileError::Import { path, problem: ImportProblem::Cycle(chain), .. }
+                if &**path == "cycle_a.nl" && chain[..] == ["cycle_a.nl", "cycle_b.nl", "cycle_a.nl"]
+        ));
+        assert!(error.to_string().contains(
+            "Import cycle through module 'cycle_a.nl': cycle_a.nl -> cycle_b.nl -> cycle_a.nl"
+        ));
+        assert!(matches!(
+            load_err("(import \"modules/answer.nl\")\n(import \"answer.nl\" a)"),
+            CompileError::Import {
+                problem: ImportProblem::Namespace,
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,76 @@
+use crate::error::Location;
+
+const TAB_WIDTH: usize = 4;
+
+/// Position of a piece of source code: byte range plus 1-based line and column
+/// of its first character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Debug)]
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+impl Source {
+    pub fn new(name: &str, text: &str) -> Self {
+        Source {
+            name: name.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    pub fn end(&self) -> Span {
+        let line = self.text.matches('\n').count() + 1;
+        let last_line = self.text.rsplit('\n').next().unwrap_or("");
+        Span {
+            start: self.text.len(),
+            end: self.text.len(),
+            line,
+            col: last_line.chars().count() + 1,
+        }
+    }
+
+    /// Renders the source line containing the span with a caret under it.
+    pub fn locate(&self, span: Span) -> Location {
+        let line_start = self.text[..span.start].rfind('\n').map_or(0, |v| v + 1);
+        let line_end = self.text[line_start..]
+            .find('\n')
+            .map_or(self.text.len(), |v| line_start + v);
+        let line = self.text[line_start..line_end].trim_end_matches('\r');
+
+        let prefix = &line[..(span.start - line_start).min(line.len())];
+        let marked = &line[prefix.len()..(span.end - line_start).clamp(prefix.len(), line.len())];
+        let padding: String = prefix
+            .chars()
+            .map(|v| {
+                if v == '\t' {
+                    " ".repeat(TAB_WIDTH)
+                } else {
+                    " ".to_string()
+                }
+            })
+            .collect();
+        let carets = "^".repeat(marked.chars().count().max(1));
+
+        let line_num = span.line.to_string();
+        Location {
+            file: self.name.clone(),
+            span,
+            excerpt: format!(
+                "  {} | {}\n  {} | {}{}",
+                line_num,
+                line.replace('\t', &" ".repeat(TAB_WIDTH)),
+                " ".repeat(line_num.len()),
+                padding,
+                carets
+            ),
+        }
+    }
+}
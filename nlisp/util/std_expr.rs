@@ -1,26 +1,69 @@
 use crate::{
+    error::{CompileError, Diagnostics, MAX_ERRORS},
     parser::parse,
-    util::{declared::Declared, expression::Expression},
+    util::{
+        declared::{Declared, Rest, Signature},
+        expression::{Expression, ExpressionType},
+        source::{Source, Span},
+        types::{annotated, Type},
+    },
 };
 
-fn declared_std() -> Declared {
+/// Signature of a built-in from its line: `name param:type... [&rest] -> type`.
+/// An unannotated parameter is `any`.
+fn built_in_signature(words: &[&str]) -> Option<Signature> {
+    let (params, result) = match words {
+        [params @ .., "->", result] => (params, Type::parse(result)?),
+        _ => return None,
+    };
+    let (params, rest) = match params {
+        [params @ .., "&rest"] => (params, Rest::Fold),
+        _ => (params, Rest::None),
+    };
+    let types = params
+        .iter()
+        .map(|v| annotated(v).map(|(_, ty)| ty.unwrap_or(Type::Any)))
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(Signature {
+        rest,
+        types,
+        result,
+        ..Signature::fixed(params.len())
+    })
+}
+
+fn declared_std() -> Result<Declared, CompileError> {
+    let built_in = Source::new("built-in", include_str!("../../resources/built-in"));
     let mut declared = Declared::new();
-    include_str!("../../resources/built-in")
-        .lines()
-        .for_each(|v| {
-            let mut words = v.split_ascii_whitespace();
+    let mut start = 0;
+    for (idx, line) in built_in.text.split('\n').enumerate() {
+        let mut words = line.split_ascii_whitespace();
+        if let Some(name) = words.next() {
+            let span = Span {
+                start,
+                end: start + name.len(),
+                line: idx + 1,
+                col: 1,
+            };
+            // `&rest` marks associative built-ins, whose calls with more
+            // arguments are nested
+            let signature = built_in_signature(&words.collect::<Vec<_>>())
+                .ok_or_else(|| ExpressionType::Type.to_error(&built_in, span))?;
             declared
-                .fn_def(words.next().unwrap(), words.count())
-                .unwrap();
-        });
+                .fn_def(name, signature)
+                .map_err(|e| e.at(built_in.locate(span)))?;
+        }
+        start += line.len() + 1;
+    }
 
-    declared
+    Ok(declared)
 }
 
-pub fn parse_std() -> (Vec<Expression>, Declared) {
-    let mut declared = declared_std();
-    let std_nl = include_str!("../../resources/std.nl").to_string();
-    let expressions = parse(std_nl, &mut declared).unwrap();
+pub fn parse_std() -> Result<(Vec<Expression>, Declared), Diagnostics> {
+    let mut declared = declared_std()?;
+    let std_nl = Source::new("std.nl", include_str!("../../resources/std.nl"));
+    let (expressions, _) = parse(&std_nl, &mut declared, Diagnostics::new(MAX_ERRORS))?;
 
-    (expressions, declared)
+    Ok((expressions, declared))
 }
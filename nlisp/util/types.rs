@@ -0,0 +1,78 @@
+use std::fmt;
+
+/// Static type of a value. `char` is a kind of `int` and `str` is a kind of
+/// `ptr`; the checker only keeps numbers and pointers apart, the narrower
+/// types document the intent. `any` is a value the checker knows nothing
+/// about: unannotated parameters, raw memory and the literal 0, which is
+/// both a number and the empty list.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Int,
+    Char,
+    Str,
+    Ptr,
+    Any,
+}
+
+impl Type {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "int" => Some(Self::Int),
+            "char" => Some(Self::Char),
+            "str" => Some(Self::Str),
+            "ptr" => Some(Self::Ptr),
+            "any" => Some(Self::Any),
+            _ => None,
+        }
+    }
+
+    pub fn is_number(self) -> bool {
+        matches!(self, Self::Int | Self::Char)
+    }
+
+    pub fn is_pointer(self) -> bool {
+        matches!(self, Self::Str | Self::Ptr)
+    }
+
+    /// Whether a value of the type `found` may be used where `self` is
+    /// expected.
+    pub fn accepts(self, found: Type) -> bool {
+        self == Self::Any
+            || found == Self::Any
+            || (self.is_number() && found.is_number())
+            || (self.is_pointer() && found.is_pointer())
+    }
+
+    /// Type of a value that is either of the two types.
+    pub fn join(self, other: Type) -> Type {
+        match (self, other) {
+            _ if self == other => self,
+            _ if self.is_number() && other.is_number() => Self::Int,
+            _ if self.is_pointer() && other.is_pointer() => Self::Ptr,
+            _ => Self::Any,
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Int => "int",
+            Self::Char => "char",
+            Self::Str => "str",
+            Self::Ptr => "ptr",
+            Self::Any => "any",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Splits `name:type` into the name and the type; a name without `:` has no
+/// type. Returns `None` if the type is unknown or the name is empty.
+pub fn annotated(atom: &str) -> Option<(&str, Option<Type>)> {
+    match atom.split_once(':') {
+        None => Some((atom, None)),
+        Some((name, ty)) if !name.is_empty() => Type::parse(ty).map(|v| (name, Some(v))),
+        Some(_) => None,
+    }
+}
@@ -5,6 +5,87 @@
 (fn < (a b) (sign (- a b)))
 (fn >= (a b) (! (sign (- a b))))
 (fn <= (a b) (! (sign (- b a))))
-(fn print_int (a) (case (sign a) 
+(fn print_int (a:int) (case (sign a) 
     (+ (out '-') (print_positive_int (* a -1))) (print_positive_int a)
-))
\ No newline at end of file
+))
+; Heap allocator. Every block is preceded by a word with its size in bytes.
+; Freed blocks form a list: its head is stored at address 12, and the first
+; word of a free block points to the next one. New blocks are cut from the
+; bump pointer at address 8 until it comes too close to the stack.
+(fn heap_error () (do (print_str "Out of memory\n") (exit 3)))
+(fn heap_grow (top) (do
+    (case (< (- (stack_pointer) top) 64) (heap_error) 0)
+    (poke32 8 top)
+))
+(fn alloc (n:int) (let size (case (> n 4) (* (/ (+ n 3) 4) 4) 4) (let prev:any 12 (let p:ptr (peek32 12) (do
+    (while (case p (< (peek32 (- p 4)) size) 0) (do (set prev p) (set p (peek32 p))))
+    (case p
+        (poke32 prev (peek32 p))
+        (do
+            (set p (+ (peek32 8) 4))
+            (heap_grow (+ p size))
+            (poke32 (- p 4) size)))
+    p
+)))))
+(fn free (p) (case p (do (poke32 p (peek32 12)) (poke32 12 p) 0) 0))
+(fn realloc (p:ptr n:int) (case p
+    (let size (peek32 (- p 4)) (case (>= size n) p
+        (case (== (+ p size) (peek32 8))
+            (let top (+ p (* (/ (+ n 3) 4) 4)) (do
+                (heap_grow top)
+                (poke32 (- p 4) (- top p))
+                p))
+            (let q (alloc n) (do
+                (for i (+ i 4) (<= i size) (poke32 (+ q (- i 4)) (peek32 (+ p (- i 4)))))
+                (free p)
+                q)))))
+    (alloc n)
+))
+(fn array (n:int) (let arr (alloc (* (+ n 1) 4)) (do
+    (poke32 arr n)
+    (for i (+ i 1) (<= i n) (poke32 (+ arr (* i 4)) 0))
+    arr
+)))
+(fn read () (let cap 16 (let s:str (alloc cap) (let n 0 (do
+    (while (poke8 (+ s n) (in)) (do
+        (set n (+ n 1))
+        (case (== n cap) (do (set cap (* cap 2)) (set s (realloc s cap))) 0)))
+    s
+)))))
+
+; Lists. A cons cell is [tag, car, cdr] and a symbol is [tag, name...]; the
+; tags are the same as in the compiler, which lays out quoted lists this way.
+; The empty list is 0.
+(fn cons (a d) (let c (alloc 12) (do
+    (poke32 c 0x434F4E01)
+    (poke32 (+ c 4) a)
+    (poke32 (+ c 8) d)
+    c
+)))
+(fn car (c) (peek32 (+ c 4)))
+(fn cdr (c) (peek32 (+ c 8)))
+(fn nil? (v) (! v))
+(fn tagged? (v tag) (case (& (>= v 16) (< v 65532)) (== (peek32 v) tag) 0))
+(fn cons? (v) (tagged? v 0x434F4E01))
+(fn symbol? (v) (tagged? v 0x53594D01))
+(fn print_atom (v) (case (symbol? v) (print_str (+ v 4))
+    (case v (print_int v) (print_str "()"))))
+(fn print_list (xs) (do
+    (out '(')
+    (while (cons? xs) (do
+        (case (cons? (car xs)) (print_list (car xs)) (print_atom (car xs)))
+        (set xs (cdr xs))
+        (case (cons? xs) (out ' ') 0)))
+    (case xs (do (print_str " . ") (print_atom xs)) 0)
+    (out ')')
+    0
+))
+(fn print_value (v) (case (cons? v) (print_list v)
+    (case (symbol? v) (print_str (+ v 4))
+        (case v (print_str v) (print_str "()")))
+))
+(fn print (v:ptr &rest more:ptr) (do
+    (print_value v)
+    (for i (+ i 1) (<= i (len more)) (print_value (get more (- i 1))))
+    0
+))
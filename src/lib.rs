@@ -10,10 +10,13 @@ pub fn decode_asm(instr: u32, fn_addresses: Option<&HashMap<String, u16>>) -> St
         0x05 => format!("mul {}", addr_mode_str(instr)),
         0x06 => format!("div {}", addr_mode_str(instr)),
         0x07 => format!("rem {}", addr_mode_str(instr)),
+        0x08 if (instr >> 22) & 0x3 == 0b11 => "jump acc".to_string(),
         0x08 => format!("jump {}", instr as u16 as i16),
         0x09 => format!("jifz {}", instr as u16 as i16),
         0x0A => {
-            if let Some(fn_addresses) = fn_addresses {
+            if (instr >> 22) & 0x3 == 0b01 {
+                format!("call {}", addr_mode_str(instr))
+            } else if let Some(fn_addresses) = fn_addresses {
                 format!(
                     "call {} -> {}",
                     instr as u16 as i16,
@@ -32,7 +35,10 @@ pub fn decode_asm(instr: u32, fn_addresses: Option<&HashMap<String, u16>>) -> St
         0x0E => format!("save {}", addr_mode_str(instr)),
         0x0F => format!("ldrel {}", addr_mode_str(instr)),
         0x10 => format!("svrel {}", addr_mode_str(instr)),
+        0x11 if (instr >> 22) & 0x3 == 0b11 => "halt acc".to_string(),
+        0x11 if instr as u16 != 0 => format!("halt {}", instr as u16),
         0x11 => "halt".to_string(),
+        0x12 => format!("ldsp {}", instr as u16 as i16),
         _ => panic!("Invalid instruction: {}", instr),
     }
 }
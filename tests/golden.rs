@@ -12,9 +12,19 @@ use serde::{Deserialize, Serialize};
 struct Golden {
     source: String,
     input: String,
+    /// Extra compiler options.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    options: Vec<String>,
     compiled: String,
     stdout: String,
     stderr: String,
+    /// Exit code of the machine, if it is not 0.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    status: i32,
+}
+
+fn is_zero(status: &i32) -> bool {
+    *status == 0
 }
 
 const SOURCES: &str = "tests/golden_sources.nl";
@@ -33,6 +43,7 @@ fn golden() -> Result<(), Box<dyn Error>> {
         temp_sources.write_all(g.source.as_bytes())?;
         let compiler_output = Command::new("target/debug/nlisp")
             .args([SOURCES, BINARY])
+            .args(&g.options)
             .output()?;
         let compiled = String::from_utf8(compiler_output.stderr)?;
 
@@ -51,9 +62,11 @@ fn golden() -> Result<(), Box<dyn Error>> {
         let new_g = Golden {
             source: g.source.clone(),
             input: g.input.clone(),
+            options: g.options.clone(),
             compiled,
             stdout: machine_stdout,
             stderr: machine_stderr,
+            status: machine_output.status.code().unwrap_or(-1),
         };
         if update {
             println!(
@@ -0,0 +1 @@
+(fn answer () 42)
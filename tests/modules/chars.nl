@@ -0,0 +1 @@
+(fn upcase_char (c) (case (& (>= c 'a') (<= c 'z')) (- c 32) c))
@@ -0,0 +1,2 @@
+(import "cycle_b.nl")
+(fn a () 1)
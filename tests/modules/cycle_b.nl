@@ -0,0 +1,2 @@
+(import "cycle_a.nl")
+(fn b () 2)
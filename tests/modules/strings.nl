@@ -0,0 +1,9 @@
+(import "chars.nl")
+
+(def calls 0)
+(fn length (s) (let n 0 (do (while (peek8 (+ s n)) (set n (+ n 1))) n)))
+(fn upper (s) (do
+    (set calls (+ calls 1))
+    (for i (+ i 1) (<= i (length s)) (poke8 (+ s (- i 1)) (upcase_char (peek8 (+ s (- i 1))))))
+    s
+))